@@ -1,8 +1,15 @@
+use std::io::{BufRead, IsTerminal, Write};
 use std::path::Path;
 
-use crate::config::DysonConfig;
-use crate::dyson::Dyson;
-use crate::summary::write_summary;
+use crate::config::{DysonConfig, SeverityConfig};
+use crate::dyson::{Dyson, DysonError};
+use crate::image::exclude_failures;
+use crate::notifier::Message;
+use crate::plan::PlanFile;
+use crate::summary::{
+    vulnerability_report, write_failures, write_reclaimed, write_summary, write_totals,
+    write_vulnerabilities,
+};
 
 /// Dyson CLI
 #[derive(clap::Parser)]
@@ -19,8 +26,8 @@ impl DysonCli {
     pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
         match &self.command {
             Commands::Init(args) => self.run_init_command(args).await,
-            Commands::Plan => self.run_plan_command().await,
-            Commands::Apply => self.run_apply_command().await,
+            Commands::Plan(args) => self.run_plan_command(args).await,
+            Commands::Apply(args) => self.run_apply_command(args).await,
         }
     }
 
@@ -43,34 +50,120 @@ impl DysonCli {
     }
 
     /// Run the plan command
-    async fn run_plan_command(&self) -> Result<(), Box<dyn std::error::Error>> {
+    async fn run_plan_command(&self, args: &PlanArgs) -> Result<(), Box<dyn std::error::Error>> {
         let dyson = self.try_new_cleaner().await?;
-        let targets = dyson.list_target_images().await?;
+        let (targets, in_use) = dyson.list_images().await?;
 
         let mut buf = Vec::new();
         write_summary(&targets, &mut std::io::BufWriter::new(&mut buf));
+        write_totals(&targets, &mut buf);
+        let layer_bytes = if args.estimate_layers {
+            Some(dyson.estimate_reclaimed_bytes(&targets).await?)
+        } else {
+            None
+        };
+        if let Some(layer_bytes) = layer_bytes {
+            write_reclaimed(&targets, layer_bytes, &mut buf);
+        }
         let summary = String::from_utf8(buf)?;
         println!("Plan Result:\n{}", summary);
-        dyson.notify_result("Plan Succeeded!!", targets).await?;
+        if let Some(severity) = args.report_vulnerabilities {
+            let mut buf = Vec::new();
+            write_vulnerabilities(&in_use, severity, &mut std::io::BufWriter::new(&mut buf));
+            let report = String::from_utf8(buf)?;
+            println!(
+                "Images in use with vulnerabilities, by the findings summary of ECR image scanning:\n{}",
+                report
+            );
+        }
+        let vulnerabilities = args
+            .report_vulnerabilities
+            .map(|severity| vulnerability_report(&in_use, severity));
+        if let Err(err) = dyson.check_limits(&targets).await {
+            println!("Warning: apply will be aborted: {}", err);
+        }
+        if let Some(out) = &args.out {
+            PlanFile::new(dyson.config_hash(), &targets).save_path(out)?;
+            println!("Saved the plan to {}", out);
+        }
+        dyson
+            .notify_result(
+                Message::new("Plan Succeeded!!", targets)
+                    .with_layer_bytes(layer_bytes)
+                    .with_vulnerabilities(vulnerabilities),
+            )
+            .await?;
         Ok(())
     }
 
     /// Run the apply command
-    async fn run_apply_command(&self) -> Result<(), Box<dyn std::error::Error>> {
+    async fn run_apply_command(&self, args: &ApplyArgs) -> Result<(), Box<dyn std::error::Error>> {
+        if !args.auto_approve && !std::io::stdin().is_terminal() {
+            return Err(
+                "refusing to apply without a TTY, use --auto-approve to skip the confirmation"
+                    .into(),
+            );
+        }
+
         let dyson = self.try_new_cleaner().await?;
-        let targets = dyson.list_target_images().await?;
+        let targets = match &args.plan_file {
+            Some(path) => {
+                let plan = PlanFile::load_path(path)?;
+                dyson
+                    .verify_plan(&plan.config_hash, plan.to_summary())
+                    .await?
+            }
+            None => dyson.list_target_images().await?,
+        };
 
         let mut buf = Vec::new();
         write_summary(&targets, &mut std::io::BufWriter::new(&mut buf));
+        write_totals(&targets, &mut buf);
         let summary = String::from_utf8(buf)?;
         println!("Following images will be deleted:\n{}", summary);
+        dyson.check_limits(&targets).await?;
+        if !args.auto_approve && !targets.is_empty() && !Self::confirm()? {
+            println!("Apply cancelled.");
+            return Ok(());
+        }
         println!("Now Applying...");
-        dyson.delete_images(&targets).await?;
-        dyson.notify_result("Apply Succeeded!!", targets).await?;
+        let failures = dyson.delete_images(&targets).await?;
+        if !failures.is_empty() {
+            let mut buf = Vec::new();
+            write_failures(&failures, &mut std::io::BufWriter::new(&mut buf));
+            let failed = String::from_utf8(buf)?;
+            println!("Following images failed to be deleted:\n{}", failed);
+            let err = DysonError::partial_deletion_error(&failures);
+            dyson
+                .notify_result(
+                    Message::new(
+                        "Apply Partially Failed!!",
+                        exclude_failures(targets, &failures),
+                    )
+                    .with_failures(failures),
+                )
+                .await?;
+            return Err(err.into());
+        }
+        dyson
+            .notify_result(Message::new("Apply Succeeded!!", targets))
+            .await?;
         println!("Apply Complete!");
         Ok(())
     }
 
+    /// Ask the user to confirm the deletion. Only `yes` is accepted
+    fn confirm() -> Result<bool, std::io::Error> {
+        print!(
+            "Do you want to perform these actions?\n  Only 'yes' will be accepted to approve.\n\n  Enter a value: "
+        );
+        std::io::stdout().flush()?;
+
+        let mut answer = String::new();
+        std::io::stdin().lock().read_line(&mut answer)?;
+        Ok(answer.trim() == "yes")
+    }
+
     /// Try to initialize a cleaner
     async fn try_new_cleaner(&self) -> Result<Dyson, Box<dyn std::error::Error>> {
         let conf = DysonConfig::load_path(&self.global_args.config_path)?;
@@ -98,9 +191,9 @@ pub enum Commands {
     /// Generate a config file
     Init(InitArgs),
     /// Make a deletion plan according to the config
-    Plan,
+    Plan(PlanArgs),
     /// Delete ECR images according to the config
-    Apply,
+    Apply(ApplyArgs),
 }
 
 /// arguments for init command
@@ -110,3 +203,29 @@ pub struct InitArgs {
     #[arg(long, default_value = "false")]
     stdout: bool,
 }
+
+/// arguments for plan command
+#[derive(clap::Args)]
+pub struct PlanArgs {
+    /// Path to write the plan file to
+    #[arg(value_name = "FILE", long)]
+    out: Option<String>,
+    /// Estimate the storage freed by the layers not shared with the kept images
+    #[arg(long, default_value = "false")]
+    estimate_layers: bool,
+    /// Report the images in use which have findings of the severity or more severe
+    #[arg(value_name = "SEVERITY", long, value_enum, ignore_case = true)]
+    report_vulnerabilities: Option<SeverityConfig>,
+}
+
+/// arguments for apply command
+#[derive(clap::Args)]
+pub struct ApplyArgs {
+    /// Path to the plan file made by the plan command.
+    /// If given, exactly the images in the plan are deleted
+    #[arg(value_name = "PLAN_FILE")]
+    plan_file: Option<String>,
+    /// Skip the interactive confirmation before deleting
+    #[arg(long, default_value = "false")]
+    auto_approve: bool,
+}
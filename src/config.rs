@@ -1,11 +1,16 @@
 use std::fmt::Debug;
 
+use sha2::{Digest, Sha256};
+
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct DysonConfig {
     /// The registry config
     pub registry: RegistryConfig,
     /// The scan configs
     pub scans: Vec<ScanConfig>,
+    /// The files listing the images to always protect
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protected_images: Option<Vec<String>>,
     /// The notification config
     pub notification: Option<NotificationConfig>,
 }
@@ -21,16 +26,187 @@ pub struct RegistryConfig {
     pub excludes: Option<Vec<String>>,
     /// The repository filters
     pub filters: Option<Vec<RepositoryFilterConfig>>,
+    /// How to evaluate the filters matching a repository
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter_mode: Option<FilterModeConfig>,
+    /// The filter for repositories which no filter matches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_filter: Option<FilterRuleConfig>,
+    /// Whether to read the overrides of the filters from the tags of the repositories, e.g. `dyson:days_after=14`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repository_overrides: Option<bool>,
+    /// Whether to read the labels of the target images, e.g. `io.dyson.keep=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_labels: Option<bool>,
+    /// The number of tagged images pushed just before the newest image in use to keep in each repository for rollbacks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rollback_buffer: Option<u64>,
+    /// The limits of deletion in a single run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limits: Option<DeletionLimitsConfig>,
+}
+
+/// How to evaluate the filters matching a repository
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterModeConfig {
+    /// All the filters matching a repository must agree, so the most restrictive one wins
+    #[default]
+    All,
+    /// The first filter matching a repository decides
+    FirstMatch,
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct RepositoryFilterConfig {
     /// The repository pattern to apply this option to
     pub pattern: String,
+    /// The rule to apply to the matched repositories
+    #[serde(flatten)]
+    pub rule: FilterRuleConfig,
+    /// The limits of deletion per repository
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limits: Option<DeletionLimitsConfig>,
+}
+
+/// The rule to decide which images in a repository are target
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct FilterRuleConfig {
     /// The number of days after which to extract images
     pub days_after: Option<u64>,
     /// The tag patterns to ignore
     pub ignore_tag_patterns: Option<Vec<String>>,
+    /// The rules for tags, each of which overrides `days_after` for the tags it matches.
+    /// The first rule matching a tag applies to it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag_rules: Option<Vec<TagRuleConfig>>,
+    /// The retention of the newest releases by semantic versions of tags
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub semver: Option<SemverFilterConfig>,
+    /// The untagged images option. Untagged images are ignored unless specified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub untagged: Option<UntaggedFilterConfig>,
+    /// The number of the most recently pushed images to keep regardless of their age
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_latest: Option<u64>,
+    /// The number of days since the last pull after which to extract images
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub days_since_last_pull: Option<u64>,
+    /// How to treat images which have never been pulled with `days_since_last_pull`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub never_pulled: Option<NeverPulledConfig>,
+    /// The option for images with vulnerabilities found by image scanning
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vulnerable: Option<VulnerableFilterConfig>,
+    /// The minimum size of images to extract in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_size_bytes: Option<i64>,
+    /// The maximum size of images to extract in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size_bytes: Option<i64>,
+}
+
+/// The rule for tags in a repository filter
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct TagRuleConfig {
+    /// The tag pattern to apply this rule to
+    pub pattern: String,
+    /// The number of days after which to extract the tags
+    pub days_after: Option<u64>,
+    /// The number of the most recently pushed images whose tags matching this rule are kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_latest: Option<u64>,
+}
+
+/// The retention of the newest releases by semantic versions of tags.
+/// Releases are kept regardless of their age, and the other tags follow the filter
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct SemverFilterConfig {
+    /// The number of the newest minor versions to keep for each major version
+    pub keep_minors: Option<u64>,
+    /// The number of the newest patch versions to keep for each minor version
+    pub keep_patches: Option<u64>,
+}
+
+/// The filter for images with vulnerabilities found by image scanning
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct VulnerableFilterConfig {
+    /// The minimum severity of the findings. Defaults to `CRITICAL`
+    pub severity: Option<SeverityConfig>,
+    /// The number of days after which to extract vulnerable images, which overrides a longer `days_after`
+    pub days_after: Option<u64>,
+}
+
+/// The severity of a finding of image scanning, in ascending order
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Deserialize,
+    serde::Serialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SeverityConfig {
+    Informational,
+    Low,
+    Medium,
+    High,
+    #[default]
+    Critical,
+}
+
+impl SeverityConfig {
+    /// Parse a severity reported by ECR
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "INFORMATIONAL" => Some(Self::Informational),
+            "LOW" => Some(Self::Low),
+            "MEDIUM" => Some(Self::Medium),
+            "HIGH" => Some(Self::High),
+            "CRITICAL" => Some(Self::Critical),
+            _ => None,
+        }
+    }
+}
+
+/// The filter for untagged images
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct UntaggedFilterConfig {
+    /// The number of days after which to extract untagged images
+    pub days_after: Option<u64>,
+}
+
+/// How to treat images which have never been pulled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NeverPulledConfig {
+    /// Use the date and time which the image was pushed instead
+    #[default]
+    PushedAt,
+    /// Always extract the image
+    Target,
+    /// Always keep the image
+    Keep,
+}
+
+/// The limits of deletion. The run is aborted if any of the limits is exceeded
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct DeletionLimitsConfig {
+    /// The maximum number of images to delete
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_images: Option<u64>,
+    /// The maximum percentage of images to delete in a repository
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_percentage: Option<f64>,
+    /// The maximum total size of images to delete in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
 }
 
 /// The notification config
@@ -61,6 +237,54 @@ pub struct ScanConfig {
     pub name: Option<String>,
     /// The AWS profile to use
     pub profile_name: String,
+    /// The options for Lambda functions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lambda: Option<LambdaScanConfig>,
+    /// The retention of task definition revisions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub task_definitions: Option<TaskDefinitionScanConfig>,
+}
+
+/// The options to scan Lambda functions
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct LambdaScanConfig {
+    /// Whether to scan all the published versions, not only the ones aliases refer to
+    pub all_versions: Option<bool>,
+}
+
+/// The retention of task definition revisions to consider as used
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct TaskDefinitionScanConfig {
+    /// The retention for the families which no family rule matches
+    #[serde(flatten)]
+    pub rule: RevisionRetentionConfig,
+    /// The retention per family pattern. The first rule matching a family applies to it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub families: Option<Vec<TaskDefinitionFamilyConfig>>,
+}
+
+/// The retention of task definition revisions for the families matching the pattern
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct TaskDefinitionFamilyConfig {
+    /// The family pattern to apply this rule to
+    pub pattern: String,
+    /// The retention, whose omitted options follow the scan
+    #[serde(flatten)]
+    pub rule: RevisionRetentionConfig,
+}
+
+/// The retention of task definition revisions
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct RevisionRetentionConfig {
+    /// The number of the latest revisions to keep in each family. Defaults to 2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_latest: Option<u64>,
+    /// The number of days within which the registered revisions are kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_days: Option<u64>,
+    /// Whether to keep INACTIVE revisions as well
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_inactive: Option<bool>,
 }
 
 impl DysonConfig {
@@ -72,6 +296,12 @@ impl DysonConfig {
         Ok(serde_yaml::from_reader(&f)?)
     }
 
+    /// The hash of the config, which is used to detect changes of the config
+    pub fn hash(&self) -> Result<String, serde_json::Error> {
+        let bytes = serde_json::to_vec(self)?;
+        Ok(format!("{:x}", Sha256::digest(bytes)))
+    }
+
     /// Example configuration
     pub fn example_config() -> Self {
         Self {
@@ -81,15 +311,39 @@ impl DysonConfig {
                 excludes: Some(vec!["exclude/*".to_string()]),
                 filters: Some(vec![RepositoryFilterConfig {
                     pattern: "*".to_string(),
-                    days_after: Some(30),
-                    ignore_tag_patterns: Some(vec!["latest".to_string()]),
+                    rule: FilterRuleConfig {
+                        days_after: Some(30),
+                        ignore_tag_patterns: Some(vec!["latest".to_string()]),
+                        tag_rules: None,
+                        semver: None,
+                        untagged: None,
+                        keep_latest: Some(3),
+                        days_since_last_pull: None,
+                        never_pulled: None,
+                        vulnerable: None,
+                        min_size_bytes: None,
+                        max_size_bytes: None,
+                    },
+                    limits: None,
                 }]),
+                filter_mode: None,
+                default_filter: None,
+                repository_overrides: None,
+                read_labels: None,
+                rollback_buffer: None,
+                limits: Some(DeletionLimitsConfig {
+                    max_images: Some(1000),
+                    max_percentage: Some(90.0),
+                    max_bytes: None,
+                }),
             },
             scans: vec![ScanConfig {
                 name: Some("scan-target".to_string()),
                 profile_name: "profile2".to_string(),
+                lambda: None,
+                task_definitions: None,
             }],
-
+            protected_images: None,
             notification: Some(NotificationConfig {
                 slack: SlackNotificationConfig {
                     webhook_url: "https://hooks.slack.com/services/xxx/yyy/zzz".to_string(),
@@ -1,15 +1,19 @@
-use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::sync::Arc;
 
 use crate::config::DysonConfig;
-use crate::image::{EcrImageId, ImagesSummary};
+use crate::image::{
+    CleanupAction, DeletionFailures, EcrImageDetail, EcrImageId, EcrImageTarget, ImagesSummary,
+};
 use crate::notifier::{Message, Notifier, SlackNotifier};
 use crate::provider::ecr::EcrImageRegistry;
 use crate::provider::ecs_service::EcsServiceImageProvider;
+use crate::provider::ecs_task::EcsTaskImageProvider;
 use crate::provider::lambda::LambdaImageProvider;
+use crate::provider::protected_images::ProtectedImagesProvider;
 use crate::provider::task_definition::TaskDefinitionProvider;
 use crate::provider::{ImageProvider, ImageRegistry};
-use aws_sdk_ecr::types::ImageIdentifier;
 use futures::future::try_join_all;
 
 /// dyson App
@@ -20,6 +24,10 @@ pub struct Dyson {
     scan_targets: Vec<Arc<dyn ImageProvider>>,
     /// notifier to notify the result
     notifier: Option<Box<dyn Notifier>>,
+    /// hash of the config to detect changes since a plan is made
+    config_hash: String,
+    /// the number of images pushed just before the newest image in use to keep in each repository
+    rollback_buffer: usize,
 }
 
 impl Dyson {
@@ -38,9 +46,28 @@ impl Dyson {
                 .profile_name(&scan.profile_name)
                 .load()
                 .await;
-            scan_targets.push(Arc::new(LambdaImageProvider::from_conf(c)));
+            scan_targets.push(Arc::new(LambdaImageProvider::from_conf(
+                c,
+                scan.lambda.as_ref(),
+            )));
             scan_targets.push(Arc::new(EcsServiceImageProvider::from_conf(c)));
-            scan_targets.push(Arc::new(TaskDefinitionProvider::from_conf(c)));
+            scan_targets.push(Arc::new(EcsTaskImageProvider::from_conf(c)));
+            scan_targets.push(Arc::new(
+                TaskDefinitionProvider::from_conf(c, scan.task_definitions.as_ref())
+                    .map_err(DysonError::initialization_error)?,
+            ));
+        }
+
+        if let Some(paths) = &conf.protected_images {
+            // the listed images are resolved against the registry
+            let c = &aws_config::from_env()
+                .profile_name(&conf.registry.profile_name)
+                .load()
+                .await;
+            scan_targets.push(Arc::new(
+                ProtectedImagesProvider::from_conf(c, paths)
+                    .map_err(DysonError::initialization_error)?,
+            ));
         }
 
         let notifier = conf
@@ -48,69 +75,294 @@ impl Dyson {
             .as_ref()
             .map(|conf| Box::new(SlackNotifier::new(&conf.slack)) as Box<dyn Notifier>);
 
+        let config_hash = conf.hash().map_err(DysonError::initialization_error)?;
+
+        let rollback_buffer = conf.registry.rollback_buffer.unwrap_or(0) as usize;
+
         Ok(Self {
             registry,
             scan_targets,
             notifier,
+            config_hash,
+            rollback_buffer,
         })
     }
 
+    /// The hash of the config
+    pub fn config_hash(&self) -> &str {
+        &self.config_hash
+    }
+
     /// List target images
     pub async fn list_target_images(&self) -> Result<ImagesSummary, DysonError> {
-        let targets = self.aggregate_target_images().await?;
+        Ok(self.list_images().await?.0)
+    }
+
+    /// List target images along with the images in use
+    pub async fn list_images(&self) -> Result<(ImagesSummary, Vec<EcrImageDetail>), DysonError> {
+        let (targets, in_use) = self.aggregate_images().await?;
         let summarized = self.summarize_tags_per_repo(&targets).await;
-        Ok(summarized)
+        Ok((summarized, in_use))
+    }
+
+    /// Verify that the planned images are still targets and return them.
+    /// The plan is stale if the config has been changed, or any of the images has been
+    /// changed, deleted or become referenced since the plan was made.
+    pub async fn verify_plan(
+        &self,
+        config_hash: &str,
+        planned: ImagesSummary,
+    ) -> Result<ImagesSummary, DysonError> {
+        if config_hash != self.config_hash {
+            return Err(DysonError::stale_plan_error(
+                "the config has been changed since the plan was made",
+            ));
+        }
+
+        // compare only what is deleted, as e.g. the last pull time may have changed since planned.
+        // the action is compared too, as a tag pushed since then turns the deletion into untagging.
+        // the tags are compared regardless of the order which ECR returns them in
+        let key = |t: &EcrImageTarget| {
+            let tags = t.target_tags.iter().cloned().collect::<BTreeSet<_>>();
+            (t.action(), t.image.id.clone(), tags, t.untagged)
+        };
+        let current = self
+            .aggregate_target_images()
+            .await?
+            .iter()
+            .map(key)
+            .collect::<HashSet<_>>();
+        let stale = planned
+            .values()
+            .flatten()
+            .filter(|t| !current.contains(&key(t)))
+            .map(|t| {
+                format!(
+                    "{}@{}",
+                    t.image.id.repository_name,
+                    t.image.id.image_digest.as_deref().unwrap_or_default()
+                )
+            })
+            .collect::<Vec<_>>();
+
+        if !stale.is_empty() {
+            return Err(DysonError::stale_plan_error(format!(
+                "the following images are no longer targets: {}",
+                stale.join(", ")
+            )));
+        }
+
+        Ok(planned)
+    }
+
+    /// aggregate target images from sources
+    async fn aggregate_target_images(&self) -> Result<HashSet<EcrImageTarget>, DysonError> {
+        Ok(self.aggregate_images().await?.0)
     }
 
-    /// aggregate images from sources
-    async fn aggregate_target_images(&self) -> Result<HashSet<EcrImageId>, DysonError> {
-        let includes = self
+    /// aggregate images from sources, and return the target images and the images in use
+    async fn aggregate_images(
+        &self,
+    ) -> Result<(HashSet<EcrImageTarget>, Vec<EcrImageDetail>), DysonError> {
+        let images = self
             .registry
-            .provide_images()
+            .collect_images()
             .await
             .map_err(DysonError::aggregation_error)?;
 
-        let excludes = try_join_all(self.scan_targets.iter().map(|s| s.provide_images()))
+        let references = try_join_all(self.scan_targets.iter().map(|s| s.provide_images()))
             .await
             .map_err(DysonError::aggregation_error)?
             .into_iter()
             .fold(HashSet::new(), |mut a, i| {
-                a.extend(i);
+                a.extend(i.iter().flat_map(|id| id.references()));
                 a
             });
+        let mut in_use = Self::resolve_digests(&images, &references);
+        let rollback = Self::rollback_images(&images, &in_use, self.rollback_buffer);
+        let by_digest = images
+            .iter()
+            .map(|t| (&t.image.id, t))
+            .collect::<HashMap<_, _>>();
+
+        // the children of image indexes which are kept are in use as well
+        let kept = images.iter().filter(|t| {
+            !t.is_target()
+                || t.action() == CleanupAction::Untag
+                || in_use.contains(&t.image.id)
+                || rollback.contains(&t.image.id)
+        });
+        in_use.extend(Self::descendants(kept, &by_digest));
+
+        let mut targets = images
+            .iter()
+            .filter(|t| t.is_target())
+            .filter(|t| !in_use.contains(&t.image.id) && !rollback.contains(&t.image.id))
+            .cloned()
+            .collect::<HashSet<_>>();
+
+        // the untagged children of image indexes to delete are deleted together
+        let deleted = targets
+            .iter()
+            .filter(|t| t.action() == CleanupAction::Delete);
+        let orphans = Self::descendants(deleted, &by_digest)
+            .into_iter()
+            .filter(|id| !in_use.contains(id))
+            .filter_map(|id| by_digest.get(&id))
+            .filter(|t| t.image.image_tags.is_empty())
+            .map(|t| EcrImageTarget::untagged(t.image.clone()))
+            .collect::<Vec<_>>();
+        targets.extend(orphans);
+
+        let in_use = images
+            .iter()
+            .filter(|t| in_use.contains(&t.image.id))
+            .map(|t| t.image.clone())
+            .collect();
+
+        Ok((targets, in_use))
+    }
+
+    /// collect the digests of all manifests referenced by the given images, recursively
+    fn descendants<'a>(
+        roots: impl Iterator<Item = &'a EcrImageTarget>,
+        by_digest: &HashMap<&EcrImageId, &'a EcrImageTarget>,
+    ) -> HashSet<EcrImageId> {
+        let mut ret = HashSet::new();
+        let mut stack = roots.collect::<Vec<_>>();
+        while let Some(target) = stack.pop() {
+            for child in target.image.child_ids() {
+                if let Some(c) = by_digest.get(&child) {
+                    if !ret.contains(&child) {
+                        stack.push(c);
+                    }
+                }
+                ret.insert(child);
+            }
+        }
+        ret
+    }
+
+    /// the images to keep for rollbacks, i.e. the `n` images pushed just before
+    /// the newest image in use in each repository, regardless of their age.
+    /// the children of image indexes are not counted.
+    fn rollback_images(
+        images: &[EcrImageTarget],
+        in_use: &HashSet<EcrImageId>,
+        n: usize,
+    ) -> HashSet<EcrImageId> {
+        if n == 0 {
+            return HashSet::new();
+        }
+
+        let children = images
+            .iter()
+            .flat_map(|t| t.image.child_ids())
+            .collect::<HashSet<_>>();
+        let mut repositories: HashMap<&str, Vec<&EcrImageDetail>> = HashMap::new();
+        images
+            .iter()
+            .map(|t| &t.image)
+            .filter(|img| !children.contains(&img.id))
+            .filter(|img| !img.image_tags.is_empty() || in_use.contains(&img.id))
+            .for_each(|img| {
+                repositories
+                    .entry(img.id.repository_name.as_str())
+                    .or_default()
+                    .push(img)
+            });
 
-        Ok(&includes - &excludes)
+        repositories
+            .into_values()
+            .flat_map(|mut images| {
+                images.sort_by_key(|i| {
+                    Reverse((i.image_pushed_at.secs(), i.image_pushed_at.subsec_nanos()))
+                });
+                let newest = images.iter().position(|i| in_use.contains(&i.id));
+                // only the tagged images can be rolled back to
+                newest
+                    .map(|p| images.split_off(p + 1))
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|i| !i.image_tags.is_empty())
+                    .take(n)
+                    .map(|i| i.id.clone())
+            })
+            .collect()
+    }
+
+    /// resolve references to the digests of images in use.
+    /// an image is in use if either its digest or any of its tags is referenced.
+    /// a tag is resolved to the image which it currently points at, so the providers report
+    /// the digests actually running where they are known.
+    fn resolve_digests(
+        images: &[EcrImageTarget],
+        references: &HashSet<EcrImageId>,
+    ) -> HashSet<EcrImageId> {
+        let mut digests = references
+            .iter()
+            .filter(|r| r.image_tag.is_none())
+            .cloned()
+            .collect::<HashSet<_>>();
+
+        digests.extend(
+            images
+                .iter()
+                .map(|t| &t.image)
+                .filter(|img| {
+                    img.image_tags
+                        .iter()
+                        .any(|tag| references.contains(&img.tag_id(tag)))
+                })
+                .map(|img| img.id.clone()),
+        );
+
+        digests
     }
 
     /// summarize images per repository
-    async fn summarize_tags_per_repo(&self, images: &HashSet<EcrImageId>) -> ImagesSummary {
-        images.iter().fold(HashMap::new(), |mut acc, image| {
-            let r = image.repository_name.clone();
-            let id = ImageIdentifier::builder()
-                .image_tag(&image.image_tag)
-                .build();
-            acc.entry(r).or_insert_with(Vec::new).push(id);
+    async fn summarize_tags_per_repo(&self, images: &HashSet<EcrImageTarget>) -> ImagesSummary {
+        images.iter().fold(HashMap::new(), |mut acc, target| {
+            let r = target.image.id.repository_name.clone();
+            acc.entry(r).or_insert_with(Vec::new).push(target.clone());
             acc
         })
     }
 
-    /// delete images from registry
-    pub async fn delete_images(&self, images: &ImagesSummary) -> Result<(), DysonError> {
+    /// delete images from registry and return the images failed to be deleted
+    pub async fn delete_images(
+        &self,
+        images: &ImagesSummary,
+    ) -> Result<DeletionFailures, DysonError> {
         self.registry
             .delete_images(images)
             .await
-            .map_err(DysonError::deletion_error)?;
-        Ok(())
+            .map_err(DysonError::deletion_error)
     }
 
-    pub async fn notify_result(
+    /// estimate the bytes actually released by deleting images, taking shared layers into account
+    pub async fn estimate_reclaimed_bytes(
         &self,
-        title: &str,
-        summary: ImagesSummary,
-    ) -> Result<(), DysonError> {
+        images: &ImagesSummary,
+    ) -> Result<i64, DysonError> {
+        self.registry
+            .estimate_reclaimed_bytes(images)
+            .await
+            .map_err(DysonError::aggregation_error)
+    }
+
+    /// check whether deleting images exceeds the limits of deletion
+    pub async fn check_limits(&self, images: &ImagesSummary) -> Result<(), DysonError> {
+        self.registry
+            .check_limits(images)
+            .await
+            .map_err(DysonError::limit_exceeded_error)
+    }
+
+    pub async fn notify_result(&self, message: Message) -> Result<(), DysonError> {
         let Some(notifier) = &self.notifier else { return Ok(()); };
         notifier
-            .notify(Message::new(title, summary))
+            .notify(message)
             .await
             .map_err(DysonError::notification_error)
     }
@@ -135,6 +387,12 @@ pub enum DysonErrorKind {
     Aggregation,
     /// An error caused by deletion.
     Deletion,
+    /// An error caused by some images failed to be deleted.
+    PartialDeletion,
+    /// An error caused by a plan which is no longer valid.
+    StalePlan,
+    /// An error caused by exceeding the limits of deletion.
+    LimitExceeded,
     /// An error caused by notification.
     Notification,
 }
@@ -170,6 +428,34 @@ impl DysonError {
         }
     }
 
+    pub fn partial_deletion_error(failures: &DeletionFailures) -> Self {
+        Self {
+            kind: DysonErrorKind::PartialDeletion,
+            source: Box::new(PartialDeletionError {
+                count: failures.values().map(|f| f.len()).sum(),
+            }),
+        }
+    }
+
+    pub fn stale_plan_error(reason: impl Into<String>) -> Self {
+        Self {
+            kind: DysonErrorKind::StalePlan,
+            source: Box::new(StalePlanError {
+                reason: reason.into(),
+            }),
+        }
+    }
+
+    pub fn limit_exceeded_error<E>(err: E) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        Self {
+            kind: DysonErrorKind::LimitExceeded,
+            source: Box::new(err),
+        }
+    }
+
     pub fn notification_error<E>(err: E) -> Self
     where
         E: std::error::Error + Send + Sync + 'static,
@@ -181,67 +467,139 @@ impl DysonError {
     }
 }
 
+/// An error reported when the registry refused to delete some images
+#[derive(Debug, thiserror::Error)]
+#[error("{} images failed to be deleted", self.count)]
+struct PartialDeletionError {
+    /// The number of images failed to be deleted
+    count: usize,
+}
+
+/// An error reported when a plan is no longer valid
+#[derive(Debug, thiserror::Error)]
+#[error("the plan is stale: {}", self.reason)]
+struct StalePlanError {
+    /// The reason why the plan is stale
+    reason: String,
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::provider::{ImageDeleter, ImageDeleterError, ImageProviderError};
+    use crate::provider::{ImageCollector, ImageDeleter, ImageDeleterError, ImageProviderError};
 
     use super::*;
 
-    #[tokio::test]
-    async fn aggregation() {
-        struct MockProvider {
-            images: HashSet<EcrImageId>,
+    struct MockRegistry {
+        images: Vec<EcrImageTarget>,
+    }
+
+    #[async_trait::async_trait]
+    impl ImageCollector for MockRegistry {
+        async fn collect_images(&self) -> Result<Vec<EcrImageTarget>, ImageProviderError> {
+            Ok(self.images.clone())
         }
 
-        #[async_trait::async_trait]
-        impl ImageProvider for MockProvider {
-            async fn provide_images(&self) -> Result<HashSet<EcrImageId>, ImageProviderError> {
-                Ok(self.images.clone())
-            }
+        async fn estimate_reclaimed_bytes(
+            &self,
+            _images: &ImagesSummary,
+        ) -> Result<i64, ImageProviderError> {
+            Ok(0)
         }
+    }
 
-        #[async_trait::async_trait]
-        impl ImageDeleter for MockProvider {
-            async fn delete_images(
-                &self,
-                _images: &ImagesSummary,
-            ) -> Result<(), ImageDeleterError> {
-                Ok(())
-            }
+    #[async_trait::async_trait]
+    impl ImageDeleter for MockRegistry {
+        async fn delete_images(
+            &self,
+            _images: &ImagesSummary,
+        ) -> Result<DeletionFailures, ImageDeleterError> {
+            Ok(DeletionFailures::new())
+        }
+
+        async fn check_limits(&self, _images: &ImagesSummary) -> Result<(), ImageDeleterError> {
+            Ok(())
+        }
+    }
+
+    impl ImageRegistry for MockRegistry {}
+
+    struct MockProvider {
+        images: HashSet<EcrImageId>,
+    }
+
+    #[async_trait::async_trait]
+    impl ImageProvider for MockProvider {
+        async fn provide_images(&self) -> Result<HashSet<EcrImageId>, ImageProviderError> {
+            Ok(self.images.clone())
+        }
+    }
+
+    /// Create a dyson with mock registry and scan targets
+    fn mock_dyson(registry: Vec<EcrImageTarget>, scanned: Vec<HashSet<EcrImageId>>) -> Dyson {
+        let scan_targets = scanned
+            .into_iter()
+            .map(|s| {
+                let p = MockProvider { images: s };
+                Arc::new(p) as Arc<dyn ImageProvider>
+            })
+            .collect();
+
+        Dyson {
+            registry: Arc::new(MockRegistry { images: registry }),
+            scan_targets,
+            notifier: None,
+            config_hash: "hash".to_string(),
+            rollback_buffer: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn aggregation() {
+        fn index(digest: &str, tags: &[&str], children: &[&str]) -> EcrImageTarget {
+            let mut target = EcrImageTarget::default_with_tags(digest, tags);
+            target.image.child_digests = children.iter().map(|c| c.to_string()).collect();
+            target
         }
 
-        impl ImageRegistry for MockProvider {}
+        fn untagged(digest: &str, is_target: bool) -> EcrImageTarget {
+            let image = EcrImageTarget::default_with_tags(digest, &[]).image;
+            if is_target {
+                EcrImageTarget::untagged(image)
+            } else {
+                EcrImageTarget::new(image, vec![])
+            }
+        }
 
         #[derive(Debug)]
         struct TestCase {
             name: String,
-            registry: HashSet<EcrImageId>,
+            registry: Vec<EcrImageTarget>,
             scanned: Vec<HashSet<EcrImageId>>,
-            expected: HashSet<EcrImageId>,
+            expected: HashSet<EcrImageTarget>,
         }
 
         let cases = vec![
             TestCase {
                 name: "scanned images is empty".to_string(),
-                registry: HashSet::from([EcrImageId::default_with_tag("test")]),
+                registry: vec![EcrImageTarget::default_with_tags("sha256:a", &["test"])],
                 scanned: vec![],
-                expected: HashSet::from([EcrImageId::default_with_tag("test")]),
+                expected: HashSet::from([EcrImageTarget::default_with_tags("sha256:a", &["test"])]),
             },
             TestCase {
                 name: "registry is empty".to_string(),
-                registry: HashSet::new(),
+                registry: vec![],
                 scanned: vec![HashSet::from([EcrImageId::default_with_tag("test")])],
                 expected: HashSet::new(),
             },
             TestCase {
                 name: "only difference".to_string(),
-                registry: HashSet::from([
-                    EcrImageId::default_with_tag("test"),
-                    EcrImageId::default_with_tag("test2"),
-                    EcrImageId::default_with_tag("test3"),
-                    EcrImageId::default_with_tag("test4"),
-                    EcrImageId::default_with_tag("test5"),
-                ]),
+                registry: vec![
+                    EcrImageTarget::default_with_tags("sha256:a", &["test"]),
+                    EcrImageTarget::default_with_tags("sha256:b", &["test2"]),
+                    EcrImageTarget::default_with_tags("sha256:c", &["test3"]),
+                    EcrImageTarget::default_with_tags("sha256:d", &["test4"]),
+                    EcrImageTarget::default_with_tags("sha256:e", &["test5"]),
+                ],
                 scanned: vec![
                     HashSet::from([
                         EcrImageId::default_with_tag("test"),
@@ -250,33 +608,211 @@ mod tests {
                     HashSet::from([EcrImageId::default_with_tag("test3")]),
                 ],
                 expected: HashSet::from([
-                    EcrImageId::default_with_tag("test4"),
-                    EcrImageId::default_with_tag("test5"),
+                    EcrImageTarget::default_with_tags("sha256:d", &["test4"]),
+                    EcrImageTarget::default_with_tags("sha256:e", &["test5"]),
                 ]),
             },
+            TestCase {
+                name: "digest reference protects every tag pointing at the digest".to_string(),
+                registry: vec![
+                    EcrImageTarget::default_with_tags("sha256:a", &["test", "test2"]),
+                    EcrImageTarget::default_with_tags("sha256:b", &["test3"]),
+                ],
+                scanned: vec![HashSet::from([EcrImageId::default_with_digest("sha256:a")])],
+                expected: HashSet::from([EcrImageTarget::default_with_tags(
+                    "sha256:b",
+                    &["test3"],
+                )]),
+            },
+            TestCase {
+                name: "image is in use if any of its tags is referenced".to_string(),
+                registry: vec![
+                    EcrImageTarget::default_with_tags("sha256:a", &["v1.2", "prod"]),
+                    EcrImageTarget::default_with_tags("sha256:b", &["v1.1"]),
+                ],
+                scanned: vec![HashSet::from([EcrImageId::default_with_tag("prod")])],
+                expected: HashSet::from([EcrImageTarget::default_with_tags("sha256:b", &["v1.1"])]),
+            },
+            TestCase {
+                name: "tag and digest of a reference are both considered".to_string(),
+                registry: vec![
+                    EcrImageTarget::default_with_tags("sha256:a", &["prod"]),
+                    EcrImageTarget::default_with_tags("sha256:b", &["v1.1"]),
+                ],
+                scanned: vec![HashSet::from([
+                    EcrImageId::default_with_tag("prod").with_digest("sha256:b")
+                ])],
+                expected: HashSet::new(),
+            },
+            TestCase {
+                name: "untagged images are target unless the digest is referenced".to_string(),
+                registry: vec![
+                    EcrImageTarget::untagged(
+                        EcrImageTarget::default_with_tags("sha256:a", &[]).image,
+                    ),
+                    EcrImageTarget::untagged(
+                        EcrImageTarget::default_with_tags("sha256:b", &[]).image,
+                    ),
+                ],
+                scanned: vec![HashSet::from([EcrImageId::default_with_digest("sha256:a")])],
+                expected: HashSet::from([EcrImageTarget::untagged(
+                    EcrImageTarget::default_with_tags("sha256:b", &[]).image,
+                )]),
+            },
+            TestCase {
+                name: "children of an image index in use are protected".to_string(),
+                registry: vec![
+                    index("sha256:i", &["v1"], &["sha256:c1", "sha256:c2"]),
+                    untagged("sha256:c1", true),
+                    untagged("sha256:c2", true),
+                    untagged("sha256:orphan", true),
+                ],
+                scanned: vec![HashSet::from([EcrImageId::default_with_tag("v1")])],
+                expected: HashSet::from([untagged("sha256:orphan", true)]),
+            },
+            TestCase {
+                name: "children of an image index to delete are deleted together".to_string(),
+                registry: vec![
+                    index("sha256:i", &["v1"], &["sha256:c1", "sha256:c2"]),
+                    untagged("sha256:c1", false),
+                    untagged("sha256:c2", false),
+                    index("sha256:j", &["v2"], &["sha256:c2", "sha256:c3"]),
+                    untagged("sha256:c3", false),
+                ],
+                scanned: vec![HashSet::from([EcrImageId::default_with_tag("v2")])],
+                expected: HashSet::from([
+                    index("sha256:i", &["v1"], &["sha256:c1", "sha256:c2"]),
+                    untagged("sha256:c1", true),
+                ]),
+            },
+            TestCase {
+                name: "images without target tags are not target".to_string(),
+                registry: vec![EcrImageTarget::new(
+                    EcrImageTarget::default_with_tags("sha256:a", &["test"]).image,
+                    vec![],
+                )],
+                scanned: vec![],
+                expected: HashSet::new(),
+            },
         ];
 
         for case in cases {
-            let registry = Arc::new(MockProvider {
-                images: case.registry,
-            });
-
-            let scan_targets = case
-                .scanned
-                .into_iter()
-                .map(|s| {
-                    let p = MockProvider { images: s };
-                    Arc::new(p) as Arc<dyn ImageProvider>
-                })
-                .collect();
-            let dyson = Dyson {
-                registry,
-                scan_targets,
-                notifier: None,
-            };
-
+            let dyson = mock_dyson(case.registry, case.scanned);
             let res = dyson.aggregate_target_images().await.unwrap();
             assert_eq!(res, case.expected, "{}", case.name);
         }
     }
+
+    #[tokio::test]
+    async fn images_in_use() {
+        let mut index = EcrImageTarget::default_with_tags("sha256:i", &["v1"]);
+        index.image.child_digests = vec!["sha256:c".to_string()];
+        let registry = vec![
+            index,
+            EcrImageTarget::new(
+                EcrImageTarget::default_with_tags("sha256:c", &[]).image,
+                vec![],
+            ),
+            EcrImageTarget::default_with_tags("sha256:b", &["v2"]),
+        ];
+        let scanned = vec![HashSet::from([EcrImageId::default_with_tag("v1")])];
+
+        let dyson = mock_dyson(registry, scanned);
+        let (_, in_use) = dyson.aggregate_images().await.unwrap();
+        let digests = in_use
+            .iter()
+            .filter_map(|i| i.id.image_digest.as_deref())
+            .collect::<HashSet<_>>();
+        assert_eq!(digests, HashSet::from(["sha256:i", "sha256:c"]));
+    }
+
+    #[tokio::test]
+    async fn rollback_buffer() {
+        fn pushed(digest: &str, tag: &str, secs: i64) -> EcrImageTarget {
+            let mut target = EcrImageTarget::default_with_tags(digest, &[tag]);
+            target.image.image_pushed_at = aws_smithy_types::DateTime::from_secs(secs);
+            target
+        }
+
+        let mut untagged = EcrImageTarget::untagged(pushed("sha256:f", "", 415).image);
+        untagged.image.image_tags = vec![];
+        let registry = vec![
+            pushed("sha256:a", "v40", 400),
+            pushed("sha256:b", "v41", 410),
+            pushed("sha256:c", "v42", 420),
+            pushed("sha256:d", "v43", 430),
+            pushed("sha256:e", "v39", 390),
+            untagged,
+        ];
+        let scanned = vec![HashSet::from([EcrImageId::default_with_tag("v42")])];
+
+        let mut dyson = mock_dyson(registry, scanned);
+        dyson.rollback_buffer = 2;
+        let res = dyson.aggregate_target_images().await.unwrap();
+        let tags = res
+            .iter()
+            .flat_map(|t| t.target_tags.clone())
+            .collect::<HashSet<_>>();
+        assert_eq!(
+            tags,
+            HashSet::from(["v43".to_string(), "v39".to_string()]),
+            "the two images pushed before the newest image in use are kept"
+        );
+        assert!(
+            res.iter()
+                .any(|t| t.image.id.image_digest.as_deref() == Some("sha256:f")),
+            "the untagged image is not counted in the buffer"
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_plan() {
+        let planned = ImagesSummary::from([(
+            "nginx".to_string(),
+            vec![EcrImageTarget::default_with_tags("sha256:a", &["v1"])],
+        )]);
+        let registry = vec![
+            EcrImageTarget::default_with_tags("sha256:a", &["v1"]),
+            EcrImageTarget::default_with_tags("sha256:b", &["v2"]),
+        ];
+
+        let dyson = mock_dyson(registry.clone(), vec![]);
+        let res = dyson.verify_plan("hash", planned.clone()).await.unwrap();
+        assert_eq!(res, planned, "only the planned images are returned");
+
+        let res = dyson.verify_plan("changed", planned.clone()).await;
+        assert!(res.is_err(), "the config has been changed");
+
+        let scanned = vec![HashSet::from([EcrImageId::default_with_tag("v1")])];
+        let dyson = mock_dyson(registry, scanned);
+        let res = dyson.verify_plan("hash", planned.clone()).await;
+        assert!(res.is_err(), "the planned image has become referenced");
+
+        let planned_tags = ImagesSummary::from([(
+            "nginx".to_string(),
+            vec![EcrImageTarget::default_with_tags(
+                "sha256:a",
+                &["v1", "v1.0"],
+            )],
+        )]);
+        let dyson = mock_dyson(
+            vec![EcrImageTarget::default_with_tags(
+                "sha256:a",
+                &["v1.0", "v1"],
+            )],
+            vec![],
+        );
+        let res = dyson.verify_plan("hash", planned_tags).await;
+        assert!(res.is_ok(), "the tags are returned in another order");
+
+        let mut retagged = EcrImageTarget::default_with_tags("sha256:a", &["v1", "stable"]);
+        retagged.target_tags = vec!["v1".to_string()];
+        let dyson = mock_dyson(vec![retagged], vec![]);
+        let res = dyson.verify_plan("hash", planned.clone()).await;
+        assert!(res.is_err(), "a tag has been added to the planned image");
+
+        let dyson = mock_dyson(vec![], vec![]);
+        let res = dyson.verify_plan("hash", planned).await;
+        assert!(res.is_err(), "the planned image has been deleted");
+    }
 }
@@ -1,10 +1,12 @@
 use aws_sdk_ecr::types::ImageIdentifier;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 
 use once_cell::sync::OnceCell;
 use regex::Regex;
 
+use crate::config::SeverityConfig;
+
 /// An image identifier in ECR
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub struct EcrImageId {
@@ -15,7 +17,9 @@ pub struct EcrImageId {
     /// The name of the image's repository.
     pub repository_name: String,
     /// The tag used for the image.
-    pub image_tag: String,
+    pub image_tag: Option<String>,
+    /// The sha256 digest of the image manifest.
+    pub image_digest: Option<String>,
 }
 
 impl EcrImageId {
@@ -29,26 +33,53 @@ impl EcrImageId {
             registry_id: registry_id.into(),
             region: region.into(),
             repository_name: repository_name.into(),
-            image_tag: image_tag.into(),
+            image_tag: Some(image_tag.into()),
+            image_digest: None,
         }
     }
 
     /// Parse an image URI into an EcrImage
+    ///
+    /// Supported forms are `repo:tag`, `repo@digest` and `repo:tag@digest`.
     pub fn from_image_uri_opt(uri: &str) -> Option<Self> {
         let pattern = {
             static RE: OnceCell<Regex> = OnceCell::new();
             RE.get_or_init(|| Regex::new(
-                r"^(?P<registry_id>\d{12})\.dkr\.ecr\.(?P<region>[a-z0-9-]+)\.amazonaws.com/(?P<repository_name>[^:]+):(?P<image_tag>[^:]+)$"
+                r"^(?P<registry_id>\d{12})\.dkr\.ecr\.(?P<region>[a-z0-9-]+)\.amazonaws.com/(?P<repository_name>[^:@]+)(:(?P<image_tag>[^:@]+))?(@(?P<image_digest>[a-z0-9]+:[a-f0-9]+))?$"
             ).unwrap())
         };
 
-        pattern.captures(uri).map(|caps| Self {
+        let caps = pattern.captures(uri)?;
+        let image_tag = caps.name("image_tag").map(|m| m.as_str().to_owned());
+        let image_digest = caps.name("image_digest").map(|m| m.as_str().to_owned());
+        if image_tag.is_none() && image_digest.is_none() {
+            return None;
+        }
+
+        Some(Self {
             registry_id: caps.name("registry_id").unwrap().as_str().to_owned(),
             region: caps.name("region").unwrap().as_str().to_owned(),
             repository_name: caps.name("repository_name").unwrap().as_str().to_owned(),
-            image_tag: caps.name("image_tag").unwrap().as_str().to_owned(),
+            image_tag,
+            image_digest,
         })
     }
+
+    /// Split into the references by which this image can be identified,
+    /// i.e. one holding only the tag and one holding only the digest.
+    pub fn references(&self) -> impl Iterator<Item = EcrImageId> + '_ {
+        let by_tag = self.image_tag.as_ref().map(|tag| Self {
+            image_tag: Some(tag.clone()),
+            image_digest: None,
+            ..self.clone()
+        });
+        let by_digest = self.image_digest.as_ref().map(|digest| Self {
+            image_tag: None,
+            image_digest: Some(digest.clone()),
+            ..self.clone()
+        });
+        by_tag.into_iter().chain(by_digest)
+    }
 }
 
 #[cfg(test)]
@@ -56,15 +87,42 @@ impl EcrImageId {
     pub fn default_with_tag(tag: impl Into<String>) -> Self {
         Self::new("123456789012", "us-east-1", "nginx", tag.into())
     }
+
+    pub fn with_digest(mut self, image_digest: impl Into<String>) -> Self {
+        self.image_digest = Some(image_digest.into());
+        self
+    }
+
+    pub fn default_with_digest(digest: impl Into<String>) -> Self {
+        Self {
+            image_tag: None,
+            ..Self::default_with_tag("")
+        }
+        .with_digest(digest)
+    }
 }
 
-/// An image in ECR
-#[derive(Debug, Clone)]
+/// An image manifest in ECR
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub struct EcrImageDetail {
-    /// image identifier
+    /// image identifier, which holds the digest of the image manifest
     pub id: EcrImageId,
+    /// the tags pointing at the image
+    pub image_tags: Vec<String>,
     /// the date and time which the image was pushed to the repository
     pub image_pushed_at: aws_smithy_types::DateTime,
+    /// the digests of the child manifests if the image is an image index
+    pub child_digests: Vec<String>,
+    /// the size of the image in bytes
+    pub image_size_in_bytes: i64,
+    /// the date and time which the image was last pulled, if ever
+    pub last_recorded_pull_time: Option<aws_smithy_types::DateTime>,
+    /// the number of findings of image scanning per severity
+    pub finding_severity_counts: BTreeMap<String, i64>,
+    /// the status of image scanning, which is missing if the image has never been scanned
+    pub scan_status: Option<String>,
+    /// the labels of the image config, which are read only if enabled
+    pub labels: BTreeMap<String, String>,
 }
 
 impl EcrImageDetail {
@@ -72,18 +130,225 @@ impl EcrImageDetail {
         registry_id: impl Into<String>,
         region: impl Into<String>,
         repository_name: impl Into<String>,
-        image_tag: impl Into<String>,
+        image_digest: impl Into<String>,
+        image_tags: Vec<String>,
         image_pushed_at: aws_smithy_types::DateTime,
     ) -> Self {
         Self {
-            id: EcrImageId::new(registry_id, region, repository_name, image_tag),
+            id: EcrImageId {
+                registry_id: registry_id.into(),
+                region: region.into(),
+                repository_name: repository_name.into(),
+                image_tag: None,
+                image_digest: Some(image_digest.into()),
+            },
+            image_tags,
             image_pushed_at,
+            child_digests: Vec::new(),
+            image_size_in_bytes: 0,
+            last_recorded_pull_time: None,
+            finding_severity_counts: BTreeMap::new(),
+            scan_status: None,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    /// The identifiers of the child manifests
+    pub fn child_ids(&self) -> impl Iterator<Item = EcrImageId> + '_ {
+        self.child_digests.iter().map(|d| EcrImageId {
+            image_tag: None,
+            image_digest: Some(d.clone()),
+            ..self.id.clone()
+        })
+    }
+
+    /// A human readable name of the image
+    pub fn display_name(&self) -> String {
+        if self.image_tags.is_empty() {
+            return format!(
+                "<untagged> {}",
+                self.id.image_digest.as_deref().unwrap_or_default()
+            );
+        }
+        self.image_tags.join(", ")
+    }
+
+    /// The number of findings of image scanning of the severity or more severe
+    pub fn findings_at_least(&self, severity: SeverityConfig) -> i64 {
+        self.finding_severity_counts
+            .iter()
+            .filter(|(s, _)| SeverityConfig::from_name(s).is_some_and(|s| s >= severity))
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// Whether the findings of image scanning are available.
+    /// `ACTIVE` is reported by enhanced scanning, which scans the image continuously.
+    pub fn is_scanned(&self) -> bool {
+        matches!(self.scan_status.as_deref(), Some("COMPLETE" | "ACTIVE"))
+    }
+
+    /// The identifier referencing the image by the given tag
+    pub fn tag_id(&self, tag: impl Into<String>) -> EcrImageId {
+        EcrImageId::new(
+            &self.id.registry_id,
+            &self.id.region,
+            &self.id.repository_name,
+            tag,
+        )
+    }
+}
+
+/// An action to take on an image
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum CleanupAction {
+    /// Remove some of the tags, but keep the image manifest
+    Untag,
+    /// Delete the image manifest along with all of its tags
+    Delete,
+}
+
+/// An image in ECR and its tags which are targets of the cleanup
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+pub struct EcrImageTarget {
+    /// the image
+    pub image: EcrImageDetail,
+    /// the tags to remove from the image
+    pub target_tags: Vec<String>,
+    /// whether the image is a target even though it has no tags
+    pub untagged: bool,
+}
+
+impl EcrImageTarget {
+    pub fn new(image: EcrImageDetail, target_tags: Vec<String>) -> Self {
+        Self {
+            image,
+            target_tags,
+            untagged: false,
+        }
+    }
+
+    /// Create a target for an untagged image
+    pub fn untagged(image: EcrImageDetail) -> Self {
+        Self {
+            image,
+            target_tags: Vec::new(),
+            untagged: true,
+        }
+    }
+
+    /// Whether any action is taken on the image
+    pub fn is_target(&self) -> bool {
+        self.untagged || !self.target_tags.is_empty()
+    }
+
+    /// The action to take on the image.
+    /// The manifest is deleted only if all of its tags are targets.
+    pub fn action(&self) -> CleanupAction {
+        if self.untagged
+            || self
+                .image
+                .image_tags
+                .iter()
+                .all(|t| self.target_tags.contains(t))
+        {
+            CleanupAction::Delete
+        } else {
+            CleanupAction::Untag
+        }
+    }
+
+    /// The size of the storage freed by the action in bytes.
+    /// Nothing is freed by untagging.
+    pub fn reclaimed_bytes(&self) -> i64 {
+        match self.action() {
+            CleanupAction::Delete => self.image.image_size_in_bytes,
+            CleanupAction::Untag => 0,
         }
     }
+
+    /// The identifiers to pass to `BatchDeleteImage`
+    pub fn image_identifiers(&self) -> Vec<ImageIdentifier> {
+        match self.action() {
+            CleanupAction::Delete => vec![ImageIdentifier::builder()
+                .set_image_digest(self.image.id.image_digest.clone())
+                .build()],
+            CleanupAction::Untag => self
+                .target_tags
+                .iter()
+                .map(|t| ImageIdentifier::builder().image_tag(t).build())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl EcrImageTarget {
+    pub fn default_with_tags(digest: impl Into<String>, tags: &[&str]) -> Self {
+        let tags = tags.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let image = EcrImageDetail::new(
+            "123456789012",
+            "us-east-1",
+            "nginx",
+            digest,
+            tags.clone(),
+            aws_smithy_types::DateTime::from_secs(0),
+        );
+        Self::new(image, tags)
+    }
+}
+
+/// A summary of target images per repository
+pub type ImagesSummary = HashMap<String, Vec<EcrImageTarget>>;
+
+/// An image which failed to be deleted
+#[derive(Debug, Clone)]
+pub struct DeletionFailure {
+    /// The identifier of the image
+    pub image_id: ImageIdentifier,
+    /// The failure code reported by the registry
+    pub code: String,
+    /// The reason of the failure
+    pub reason: String,
+}
+
+impl DeletionFailure {
+    /// A human readable name of the image
+    pub fn display_name(&self) -> &str {
+        self.image_id
+            .image_tag()
+            .or_else(|| self.image_id.image_digest())
+            .unwrap_or_default()
+    }
 }
 
-/// A summary of image tags per repository
-pub type ImagesSummary = HashMap<String, Vec<ImageIdentifier>>;
+/// Images failed to be deleted per repository
+pub type DeletionFailures = HashMap<String, Vec<DeletionFailure>>;
+
+/// Remove the targets any of whose identifiers failed to be deleted,
+/// so that only the images actually cleaned up are reported
+pub fn exclude_failures(summary: ImagesSummary, failures: &DeletionFailures) -> ImagesSummary {
+    summary
+        .into_iter()
+        .map(|(repo, targets)| {
+            let Some(failures) = failures.get(&repo) else { return (repo, targets); };
+            let targets = targets
+                .into_iter()
+                .filter(|t| {
+                    !t.image_identifiers().iter().any(|id| {
+                        failures.iter().any(|f| {
+                            id.image_tag().is_some() && id.image_tag() == f.image_id.image_tag()
+                                || id.image_digest().is_some()
+                                    && id.image_digest() == f.image_id.image_digest()
+                        })
+                    })
+                })
+                .collect::<Vec<_>>();
+            (repo, targets)
+        })
+        .filter(|(_, targets)| !targets.is_empty())
+        .collect()
+}
 
 #[cfg(test)]
 mod tests {
@@ -101,13 +366,110 @@ mod tests {
                     registry_id: "123456789012".to_string(),
                     region: "us-east-1".to_string(),
                     repository_name: "A/b".to_string(),
-                    image_tag: "latest".to_string(),
+                    image_tag: Some("latest".to_string()),
+                    image_digest: None,
+                }),
+            ),
+            (
+                "123456789012.dkr.ecr.us-east-1.amazonaws.com/A/b@sha256:0123abcd",
+                Some(EcrImageId {
+                    registry_id: "123456789012".to_string(),
+                    region: "us-east-1".to_string(),
+                    repository_name: "A/b".to_string(),
+                    image_tag: None,
+                    image_digest: Some("sha256:0123abcd".to_string()),
                 }),
             ),
+            (
+                "123456789012.dkr.ecr.us-east-1.amazonaws.com/A/b:latest@sha256:0123abcd",
+                Some(EcrImageId {
+                    registry_id: "123456789012".to_string(),
+                    region: "us-east-1".to_string(),
+                    repository_name: "A/b".to_string(),
+                    image_tag: Some("latest".to_string()),
+                    image_digest: Some("sha256:0123abcd".to_string()),
+                }),
+            ),
+            ("123456789012.dkr.ecr.us-east-1.amazonaws.com/A/b", None),
         ];
 
         for (input, expected) in cases {
             assert_eq!(EcrImageId::from_image_uri_opt(input), expected);
         }
     }
+
+    #[test]
+    fn cleanup_action() {
+        let mut target = EcrImageTarget::default_with_tags("sha256:aaa", &["v1.2", "prod"]);
+        assert_eq!(target.action(), CleanupAction::Delete);
+        assert_eq!(
+            target.image_identifiers(),
+            vec![ImageIdentifier::builder()
+                .image_digest("sha256:aaa")
+                .build()]
+        );
+
+        target.target_tags = vec!["v1.2".to_string()];
+        assert_eq!(target.action(), CleanupAction::Untag);
+        assert_eq!(
+            target.image_identifiers(),
+            vec![ImageIdentifier::builder().image_tag("v1.2").build()]
+        );
+    }
+
+    #[test]
+    fn excluding_failures() {
+        let mut untagged = EcrImageTarget::default_with_tags("sha256:bbb", &["v1.1", "stable"]);
+        untagged.target_tags = vec!["v1.1".to_string()];
+        let summary = ImagesSummary::from([
+            (
+                "nginx".to_string(),
+                vec![
+                    EcrImageTarget::default_with_tags("sha256:aaa", &["v1.0"]),
+                    untagged,
+                    EcrImageTarget::default_with_tags("sha256:ccc", &["v1.2"]),
+                ],
+            ),
+            (
+                "redis".to_string(),
+                vec![EcrImageTarget::default_with_tags("sha256:ddd", &["7.0"])],
+            ),
+        ]);
+        let failure = |id: ImageIdentifier| DeletionFailure {
+            image_id: id,
+            code: "ImageReferencedByManifestList".to_string(),
+            reason: String::new(),
+        };
+        let failures = DeletionFailures::from([
+            (
+                "nginx".to_string(),
+                vec![
+                    failure(
+                        ImageIdentifier::builder()
+                            .image_digest("sha256:aaa")
+                            .build(),
+                    ),
+                    failure(ImageIdentifier::builder().image_tag("v1.1").build()),
+                ],
+            ),
+            (
+                "redis".to_string(),
+                vec![failure(
+                    ImageIdentifier::builder()
+                        .image_digest("sha256:ddd")
+                        .build(),
+                )],
+            ),
+        ]);
+
+        let actual = exclude_failures(summary, &failures);
+        assert_eq!(actual.keys().collect::<Vec<_>>(), vec!["nginx"]);
+        assert_eq!(
+            actual["nginx"]
+                .iter()
+                .map(|t| t.image.id.image_digest.as_deref())
+                .collect::<Vec<_>>(),
+            vec![Some("sha256:ccc")]
+        );
+    }
 }
@@ -5,6 +5,8 @@ mod config;
 mod dyson;
 mod image;
 mod notifier;
+mod pattern;
+mod plan;
 mod provider;
 mod summary;
 mod utils;
@@ -1,5 +1,6 @@
 use crate::config::SlackNotificationConfig;
-use crate::image::ImagesSummary;
+use crate::image::{CleanupAction, DeletionFailures, ImagesSummary};
+use crate::summary::{format_size, repository_bytes};
 
 /// An error that can occur during the notification process.
 #[derive(Debug, thiserror::Error)]
@@ -21,6 +22,12 @@ pub struct Message {
     title: String,
     /// The body of the message
     summary: ImagesSummary,
+    /// The images failed to be deleted
+    failures: DeletionFailures,
+    /// The estimated bytes released by deleting unique layers
+    layer_bytes: Option<i64>,
+    /// The images in use to report along with their findings, as repository, image and findings
+    vulnerabilities: Option<Vec<(String, String, String)>>,
 }
 
 impl Message {
@@ -28,8 +35,32 @@ impl Message {
         Self {
             title: title.into(),
             summary,
+            failures: DeletionFailures::new(),
+            layer_bytes: None,
+            vulnerabilities: None,
         }
     }
+
+    /// Set the images failed to be deleted
+    pub fn with_failures(mut self, failures: DeletionFailures) -> Self {
+        self.failures = failures;
+        self
+    }
+
+    /// Set the estimated bytes released by deleting unique layers
+    pub fn with_layer_bytes(mut self, layer_bytes: Option<i64>) -> Self {
+        self.layer_bytes = layer_bytes;
+        self
+    }
+
+    /// Set the report of the images in use with vulnerabilities
+    pub fn with_vulnerabilities(
+        mut self,
+        vulnerabilities: Option<Vec<(String, String, String)>>,
+    ) -> Self {
+        self.vulnerabilities = vulnerabilities;
+        self
+    }
 }
 
 pub struct SlackNotifier {
@@ -56,21 +87,88 @@ impl SlackNotifier {
 impl Notifier for SlackNotifier {
     async fn notify(&self, message: Message) -> Result<(), NotificationError> {
         let result = message.summary.iter().fold(
-            String::from("Repo | Count\n----------------\n"),
-            |acc, (key, value)| format!("{}{} | {}\n", acc, key, value.len()),
+            String::from("Repo | Delete | Untag | Size\n----------------\n"),
+            |acc, (key, value)| {
+                let deleted = value
+                    .iter()
+                    .filter(|t| t.action() == CleanupAction::Delete)
+                    .count();
+                let acc = format!(
+                    "{}{} | {} | {} | {}\n",
+                    acc,
+                    key,
+                    deleted,
+                    value.len() - deleted,
+                    format_size(repository_bytes(value))
+                );
+                // the images along with their sizes, as in the summary of the terminal
+                value.iter().fold(acc, |acc, t| match t.action() {
+                    CleanupAction::Delete => format!(
+                        "{}  {} ({})\n",
+                        acc,
+                        t.image.display_name(),
+                        format_size(t.reclaimed_bytes())
+                    ),
+                    CleanupAction::Untag => {
+                        format!("{}  {} (untag only)\n", acc, t.target_tags.join(", "))
+                    }
+                })
+            },
+        );
+        let total_bytes = message.summary.values().map(|v| repository_bytes(v)).sum();
+        let mut result = format!(
+            "{}----------------\nTotal: {}",
+            result,
+            format_size(total_bytes)
         );
+        if let Some(layer_bytes) = message.layer_bytes {
+            result.push_str(&format!(" (unique layers: {})", format_size(layer_bytes)));
+        }
+        result.push('\n');
+
+        let mut fields = vec![serde_json::json!({
+            "title": message.title,
+            "value": format!("```{}```", result),
+            "short": false
+        })];
+        let mut color = "#36a64f";
+
+        if let Some(vulnerabilities) = &message.vulnerabilities {
+            let report = vulnerabilities.iter().fold(
+                String::from("Repo | Image | Findings\n----------------\n"),
+                |acc, (repo, image, findings)| {
+                    format!("{}{} | {} | {}\n", acc, repo, image, findings)
+                },
+            );
+            fields.push(serde_json::json!({
+                "title": "Vulnerabilities (ECR image scanning findings summary)",
+                "value": format!("```{}```", report),
+                "short": false
+            }));
+        }
+
+        if !message.failures.is_empty() {
+            let failed = message.failures.iter().fold(
+                String::from("Repo | Image | Code\n----------------\n"),
+                |acc, (key, value)| {
+                    value.iter().fold(acc, |acc, f| {
+                        format!("{}{} | {} | {}\n", acc, key, f.display_name(), f.code)
+                    })
+                },
+            );
+            fields.push(serde_json::json!({
+                "title": "Failures",
+                "value": format!("```{}```", failed),
+                "short": false
+            }));
+            color = "#a30200";
+        }
 
         let mut payload = serde_json::json!({
             "attachments": [
                 {
-                    "color": "#36a64f",
-                    "fields": [
-                      {
-                        "title": message.title,
-                        "value": format!("```{}```", result),
-                        "short": false
-                      }
-                    ]
+                    "color": color,
+                    "fields": fields
                 }
             ]
         });
@@ -0,0 +1,94 @@
+use regex::Regex;
+
+/// The prefix of a pattern which is a regular expression
+const REGEX_PREFIX: &str = "regex:";
+
+/// A pattern for names such as repositories and tags.
+/// Patterns prefixed with `regex:` are regular expressions, and the others are globs.
+/// Both have to match the whole name.
+#[derive(Debug, Clone)]
+pub enum NamePattern {
+    /// A glob pattern
+    Glob(glob::Pattern),
+    /// A regular expression
+    Regex(Regex),
+}
+
+impl NamePattern {
+    /// Parse a pattern
+    pub fn new(pattern: &str) -> Result<Self, InvalidPatternError> {
+        let parsed = match pattern.strip_prefix(REGEX_PREFIX) {
+            Some(re) => Regex::new(&format!("^(?:{})$", re))
+                .map(Self::Regex)
+                .map_err(|e| e.into()),
+            None => glob::Pattern::new(pattern)
+                .map(Self::Glob)
+                .map_err(|e| e.into()),
+        };
+
+        parsed.map_err(|source| InvalidPatternError {
+            pattern: pattern.to_owned(),
+            source,
+        })
+    }
+
+    /// Decide whether the name matches the pattern
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Glob(p) => p.matches(name),
+            Self::Regex(re) => re.is_match(name),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Default for NamePattern {
+    fn default() -> Self {
+        Self::Glob(Default::default())
+    }
+}
+
+/// An error reported when a pattern cannot be parsed
+#[derive(Debug, thiserror::Error)]
+#[error("invalid pattern `{pattern}`: {source}")]
+pub struct InvalidPatternError {
+    /// The offending pattern
+    pattern: String,
+    /// The cause of the error
+    source: Box<dyn std::error::Error + Send + Sync>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches() {
+        let cases = vec![
+            ("match-*", "match-1", true),
+            ("match-*", "dummy-1", false),
+            (r"regex:^pr-\d+-[0-9a-f]{7}$", "pr-12-0123abc", true),
+            (r"regex:^pr-\d+-[0-9a-f]{7}$", "pr-12-0123abc-dirty", false),
+            (r"regex:^v\d+\.\d+\.\d+(-.+)?$", "v1.2.3-rc.1", true),
+            (r"regex:^v\d+\.\d+\.\d+(-.+)?$", "latest", false),
+            (r"regex:pr-\d+", "pr-12", true),
+            (r"regex:pr-\d+", "pr-12-dirty", false),
+            (r"regex:pr-\d+", "old-pr-12", false),
+            (r"regex:dev|pr-\d+", "pr-12", true),
+            (r"regex:dev|pr-\d+", "dev-1", false),
+        ];
+
+        for (pattern, name, expected) in cases {
+            let actual = NamePattern::new(pattern).unwrap().matches(name);
+            assert_eq!(actual, expected, "{} {}", pattern, name);
+        }
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        for pattern in ["[a-", "regex:(pr-"] {
+            let err = NamePattern::new(pattern).unwrap_err();
+            assert!(err.to_string().contains(pattern), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use aws_smithy_types::DateTime;
+
+use crate::image::{EcrImageDetail, EcrImageTarget, ImagesSummary};
+
+/// The version of the plan file format
+const PLAN_FILE_VERSION: u32 = 1;
+
+/// A deletion plan persisted to a file
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct PlanFile {
+    /// The version of the plan file format
+    pub version: u32,
+    /// The hash of the config which the plan is made with
+    pub config_hash: String,
+    /// The AWS account ID of the registry
+    pub account: Option<String>,
+    /// The AWS region of the registry
+    pub region: Option<String>,
+    /// The images to clean up
+    pub images: Vec<PlannedImage>,
+}
+
+/// An image to clean up in a plan file
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct PlannedImage {
+    /// The name of the repository
+    pub repository_name: String,
+    /// The digest of the image manifest
+    pub image_digest: String,
+    /// The tags pointing at the image when planned
+    pub image_tags: Vec<String>,
+    /// The tags to remove from the image
+    pub target_tags: Vec<String>,
+    /// Whether the image is an untagged target
+    pub untagged: bool,
+    /// The date and time which the image was pushed to the repository
+    #[serde(with = "date_time_format")]
+    pub image_pushed_at: DateTime,
+    /// The digests of the child manifests if the image is an image index
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub child_digests: Vec<String>,
+    /// The size of the image in bytes
+    #[serde(default)]
+    pub image_size_in_bytes: i64,
+}
+
+impl PlanFile {
+    /// Create a plan file from the target images
+    pub fn new(config_hash: impl Into<String>, summary: &ImagesSummary) -> Self {
+        let targets = summary.values().flatten().collect::<Vec<_>>();
+        let first = targets.first().map(|t| &t.image.id);
+
+        let mut images = targets
+            .iter()
+            .map(|t| PlannedImage {
+                repository_name: t.image.id.repository_name.clone(),
+                image_digest: t.image.id.image_digest.clone().unwrap_or_default(),
+                image_tags: t.image.image_tags.clone(),
+                target_tags: t.target_tags.clone(),
+                untagged: t.untagged,
+                image_pushed_at: t.image.image_pushed_at,
+                child_digests: t.image.child_digests.clone(),
+                image_size_in_bytes: t.image.image_size_in_bytes,
+            })
+            .collect::<Vec<_>>();
+        images.sort_by(|a, b| {
+            (&a.repository_name, &a.image_digest).cmp(&(&b.repository_name, &b.image_digest))
+        });
+
+        Self {
+            version: PLAN_FILE_VERSION,
+            config_hash: config_hash.into(),
+            account: first.map(|id| id.registry_id.clone()),
+            region: first.map(|id| id.region.clone()),
+            images,
+        }
+    }
+
+    /// Load a plan from a file
+    pub fn load_path(
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let f = std::fs::File::open(&path)?;
+        let plan: Self = serde_json::from_reader(&f)?;
+        if plan.version != PLAN_FILE_VERSION {
+            return Err(format!("unsupported plan file version: {}", plan.version).into());
+        }
+        Ok(plan)
+    }
+
+    /// Save the plan to a file
+    pub fn save_path(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let f = std::fs::File::create(&path)?;
+        serde_json::to_writer_pretty(&f, self)?;
+        Ok(())
+    }
+
+    /// The target images in the plan
+    pub fn to_summary(&self) -> ImagesSummary {
+        let account = self.account.clone().unwrap_or_default();
+        let region = self.region.clone().unwrap_or_default();
+
+        self.images.iter().fold(HashMap::new(), |mut acc, planned| {
+            let mut image = EcrImageDetail::new(
+                &account,
+                &region,
+                &planned.repository_name,
+                &planned.image_digest,
+                planned.image_tags.clone(),
+                planned.image_pushed_at,
+            );
+            image.child_digests = planned.child_digests.clone();
+            image.image_size_in_bytes = planned.image_size_in_bytes;
+
+            let target = if planned.untagged {
+                EcrImageTarget::untagged(image)
+            } else {
+                EcrImageTarget::new(image, planned.target_tags.clone())
+            };
+            acc.entry(planned.repository_name.clone())
+                .or_insert_with(Vec::new)
+                .push(target);
+            acc
+        })
+    }
+}
+
+/// (De)serialize a date time in RFC 3339 format
+mod date_time_format {
+    use aws_smithy_types::date_time::Format;
+    use aws_smithy_types::DateTime;
+    use serde::de::Error as _;
+    use serde::ser::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(dt: &DateTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&dt.fmt(Format::DateTime).map_err(S::Error::custom)?)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        DateTime::from_str(&s, Format::DateTime).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let summary = ImagesSummary::from([(
+            "nginx".to_string(),
+            vec![
+                EcrImageTarget::default_with_tags("sha256:a", &["v1", "prod"]),
+                EcrImageTarget::untagged(EcrImageTarget::default_with_tags("sha256:b", &[]).image),
+            ],
+        )]);
+
+        let plan = PlanFile::new("hash", &summary);
+        let json = serde_json::to_string(&plan).unwrap();
+        let loaded: PlanFile = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(loaded, plan);
+        assert_eq!(loaded.account.as_deref(), Some("123456789012"));
+        assert_eq!(loaded.region.as_deref(), Some("us-east-1"));
+        assert_eq!(loaded.to_summary(), summary);
+    }
+}
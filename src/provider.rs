@@ -3,11 +3,13 @@ use std::fmt::Debug;
 
 use aws_smithy_http::result::SdkError;
 
-use crate::image::{EcrImageId, ImagesSummary};
+use crate::image::{DeletionFailures, EcrImageId, EcrImageTarget, ImagesSummary};
 
 pub mod ecr;
 pub mod ecs_service;
+pub mod ecs_task;
 pub mod lambda;
+pub mod protected_images;
 pub mod task_definition;
 
 /// ImageProvider is a trait for providing images
@@ -17,6 +19,20 @@ pub trait ImageProvider {
     async fn provide_images(&self) -> Result<HashSet<EcrImageId>, ImageProviderError>;
 }
 
+/// ImageCollector is a trait for collecting images stored in a registry
+#[async_trait::async_trait]
+pub trait ImageCollector {
+    /// Collect all images in the registry along with their target tags
+    async fn collect_images(&self) -> Result<Vec<EcrImageTarget>, ImageProviderError>;
+
+    /// Estimate the bytes actually released by deleting `images`,
+    /// excluding the layers shared with the images kept in the registry.
+    async fn estimate_reclaimed_bytes(
+        &self,
+        images: &ImagesSummary,
+    ) -> Result<i64, ImageProviderError>;
+}
+
 /// An error returned an ImageProvider
 #[derive(Debug, thiserror::Error)]
 #[error("[ImageProviderError] kind: {:?}, source: {}", self.kind, self.source)]
@@ -34,6 +50,10 @@ pub enum ImageProviderErrorKind {
     InitializationError,
     /// An error caused by AWS SDK.
     SdkError,
+    /// An error caused by an invalid image manifest.
+    InvalidManifest,
+    /// An error caused by downloading a blob.
+    DownloadError,
 }
 
 impl ImageProviderError {
@@ -46,6 +66,26 @@ impl ImageProviderError {
             source: Box::new(err),
         }
     }
+
+    pub fn manifest_error<T>(err: T) -> Self
+    where
+        T: std::error::Error + Send + Sync + 'static,
+    {
+        Self {
+            kind: ImageProviderErrorKind::InvalidManifest,
+            source: Box::new(err),
+        }
+    }
+
+    pub fn download_error<T>(err: T) -> Self
+    where
+        T: std::error::Error + Send + Sync + 'static,
+    {
+        Self {
+            kind: ImageProviderErrorKind::DownloadError,
+            source: Box::new(err),
+        }
+    }
 }
 
 impl<T> From<SdkError<T>> for ImageProviderError
@@ -64,7 +104,14 @@ where
 #[async_trait::async_trait]
 pub trait ImageDeleter {
     /// Delete images given by `images`.
-    async fn delete_images(&self, images: &ImagesSummary) -> Result<(), ImageDeleterError>;
+    /// Images which the registry refused to delete are returned as failures.
+    async fn delete_images(
+        &self,
+        images: &ImagesSummary,
+    ) -> Result<DeletionFailures, ImageDeleterError>;
+
+    /// Check whether deleting `images` exceeds the limits of deletion.
+    async fn check_limits(&self, images: &ImagesSummary) -> Result<(), ImageDeleterError>;
 }
 
 /// An error returned an ImageDeleter
@@ -82,6 +129,25 @@ pub struct ImageDeleterError {
 pub enum ImageDeleterErrorKind {
     /// An error caused by AWS SDK.
     SdkError,
+    /// An error caused by exceeding the limits of deletion.
+    LimitExceeded,
+}
+
+impl ImageDeleterError {
+    pub fn limit_exceeded_error(violations: Vec<String>) -> Self {
+        Self {
+            kind: ImageDeleterErrorKind::LimitExceeded,
+            source: Box::new(LimitExceededError { violations }),
+        }
+    }
+}
+
+/// An error reported when the limits of deletion are exceeded
+#[derive(Debug, thiserror::Error)]
+#[error("deletion limits exceeded: {}", self.violations.join("; "))]
+struct LimitExceededError {
+    /// The descriptions of the exceeded limits
+    violations: Vec<String>,
 }
 
 impl<T> From<SdkError<T>> for ImageDeleterError
@@ -96,5 +162,5 @@ where
     }
 }
 
-/// ImageRegistry implements ImageProvider and ImageCleaner
-pub trait ImageRegistry: ImageProvider + ImageDeleter {}
+/// ImageRegistry implements ImageCollector and ImageCleaner
+pub trait ImageRegistry: ImageCollector + ImageDeleter {}
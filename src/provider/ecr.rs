@@ -1,17 +1,28 @@
-use std::collections::HashSet;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
-use aws_sdk_ecr::types::{DescribeImagesFilter, ImageDetail, Repository, TagStatus};
+use aws_sdk_ecr::types::{
+    Image, ImageDetail, ImageFailure, ImageFailureCode, ImageIdentifier, Repository, Tag,
+};
 use futures::TryStreamExt;
+use once_cell::sync::OnceCell;
+use regex::Regex;
 use tokio::task::JoinSet;
 use tokio_stream::StreamExt;
 
-use crate::config::{RegistryConfig, RepositoryFilterConfig};
-use crate::image::{EcrImageDetail, EcrImageId, ImagesSummary};
+use crate::config::{
+    DeletionLimitsConfig, FilterModeConfig, FilterRuleConfig, NeverPulledConfig, RegistryConfig,
+    RepositoryFilterConfig, SemverFilterConfig, SeverityConfig, TagRuleConfig,
+};
+use crate::image::{
+    CleanupAction, DeletionFailure, DeletionFailures, EcrImageDetail, EcrImageTarget, ImagesSummary,
+};
+use crate::pattern::NamePattern;
+use crate::provider::{ImageCollector, ImageProviderError};
 use crate::provider::{ImageDeleter, ImageDeleterError, ImageRegistry};
-use crate::provider::{ImageProvider, ImageProviderError};
-use crate::utils::try_join_set_to_stream;
+use crate::utils::{days_before, try_join_set_to_stream};
 
 /// An ECR image Registry
 pub struct EcrImageRegistry {
@@ -21,6 +32,12 @@ pub struct EcrImageRegistry {
     filter: Arc<ImageFilter>,
     /// The repository excluder
     excluder: Arc<RepositoryExcluder>,
+    /// The limits of deletion for the whole registry
+    limits: DeletionLimits,
+    /// Whether to read the overrides from the tags of the repositories
+    read_overrides: bool,
+    /// The HTTP client to download the image configs, which is given only if the labels are read
+    label_client: Option<reqwest::Client>,
 }
 
 impl EcrImageRegistry {
@@ -34,22 +51,30 @@ impl EcrImageRegistry {
 
         let filter = Arc::new(ImageFilter::try_new(
             conf.filters.as_ref().unwrap_or(&Vec::new()),
+            conf.filter_mode.unwrap_or_default(),
+            conf.default_filter.as_ref(),
         )?);
         let excluder = Arc::new(RepositoryExcluder::new(
             conf.excludes.as_ref().unwrap_or(&Vec::new()),
         )?);
+        let limits = DeletionLimits::new(conf.limits.as_ref());
+        let read_overrides = conf.repository_overrides.unwrap_or(false);
+        let label_client = conf.read_labels.unwrap_or(false).then(reqwest::Client::new);
 
         Ok(Self {
             client,
             filter,
             excluder,
+            limits,
+            read_overrides,
+            label_client,
         })
     }
 }
 
 #[async_trait::async_trait]
-impl ImageProvider for EcrImageRegistry {
-    async fn provide_images(&self) -> Result<HashSet<EcrImageId>, ImageProviderError> {
+impl ImageCollector for EcrImageRegistry {
+    async fn collect_images(&self) -> Result<Vec<EcrImageTarget>, ImageProviderError> {
         let repos: Vec<Repository> = self
             .client
             .describe_repositories()
@@ -64,9 +89,12 @@ impl ImageProvider for EcrImageRegistry {
         repos.into_iter().for_each(|r| {
             let client = self.client.clone();
             let filter = self.filter.clone();
+            let label_client = self.label_client.clone();
+            let read_overrides = self.read_overrides;
             let Some(registry_id) = r.registry_id().map(|s| s.to_owned()) else { return; };
             let Some(repository_name) = r.repository_name().map(|s| s.to_owned()) else { return; };
             let Some(region) = client.conf().region().map(|s| s.to_string()) else { return; };
+            let Some(repository_arn) = r.repository_arn().map(|s| s.to_owned()) else { return; };
 
             // Skip if the repository is excluded
             if self.excluder.is_excluded(&repository_name) {
@@ -74,115 +102,959 @@ impl ImageProvider for EcrImageRegistry {
             }
 
             tasks.spawn(async move {
+                // reading the tags needs `ecr:ListTagsForResource` in addition
+                let overrides = if read_overrides {
+                    let tags = client
+                        .list_tags_for_resource()
+                        .resource_arn(&repository_arn)
+                        .send()
+                        .await?;
+                    RepositoryOverrides::try_from_tags(
+                        &repository_name,
+                        tags.tags().unwrap_or_default(),
+                    )?
+                } else {
+                    RepositoryOverrides::default()
+                };
+                // Skip if the repository is excluded by its owner
+                if overrides.exclude {
+                    return Ok(Vec::new());
+                }
+                let filter = filter.for_repository(&repository_name, &overrides)?;
+
                 let details: Vec<ImageDetail> = client
                     .describe_images()
                     .repository_name(&repository_name)
-                    .filter(
-                        DescribeImagesFilter::builder()
-                            .tag_status(TagStatus::Tagged)
-                            .build(),
-                    ) // Note: currently only tagged images are supported
                     .into_paginator()
                     .items()
                     .send()
                     .collect::<Result<Vec<_>, _>>()
                     .await?;
 
-                let mut targets = Vec::new();
+                let index_digests = details
+                    .iter()
+                    .filter(|d| d.image_manifest_media_type().is_some_and(is_image_index))
+                    .filter_map(|d| d.image_digest().map(|s| s.to_owned()))
+                    .collect::<Vec<_>>();
+                let mut children =
+                    describe_child_digests(&client, &repository_name, &index_digests).await?;
+
+                let mut images = Vec::new();
                 for detail in details {
                     let Some(pushed_at) = detail.image_pushed_at().map(|s| s.to_owned()) else { continue; };
-                    let Some(tags) = detail.image_tags().map(|s| s.to_owned()) else { continue; };
+                    let Some(digest) = detail.image_digest().map(|s| s.to_owned()) else { continue; };
+                    let tags = detail
+                        .image_tags()
+                        .map(|s| s.to_owned())
+                        .unwrap_or_default();
+                    let child_digests = children.remove(&digest).unwrap_or_default();
 
-                    let filtered = tags.iter().map(|t| EcrImageDetail::new(
+                    let mut image = EcrImageDetail::new(
                         &registry_id,
                         &region,
                         &repository_name,
-                        t,
+                        digest,
+                        tags,
                         pushed_at,
-                    ))
-                        .filter(|img| filter.is_match(img, now))
-                        .map(|img| img.id)
-                        .collect::<HashSet<_>>();
+                    );
+                    image.child_digests = child_digests;
+                    image.image_size_in_bytes = detail.image_size_in_bytes().unwrap_or_default();
+                    image.last_recorded_pull_time = detail.last_recorded_pull_time().cloned();
+                    image.finding_severity_counts = detail
+                        .image_scan_findings_summary()
+                        .and_then(|s| s.finding_severity_counts())
+                        .map(|counts| {
+                            counts
+                                .iter()
+                                .map(|(s, c)| (s.as_str().to_owned(), *c as i64))
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    image.scan_status = detail
+                        .image_scan_status()
+                        .and_then(|s| s.status())
+                        .map(|s| s.as_str().to_owned());
 
-                    targets.extend(filtered);
+                    images.push(image);
                 }
 
-                Ok::<_, ImageProviderError>(targets)
+                let Some(label_client) = label_client else { return Ok::<_, ImageProviderError>(filter.select(images, now)); };
+
+                // only the labels of the candidates are read since downloading the configs is costly
+                let candidates = filter.select(images, now);
+                let mut labels = describe_labels(
+                    &client,
+                    &label_client,
+                    &repository_name,
+                    &candidates
+                        .iter()
+                        .filter(|t| t.is_target())
+                        .map(|t| &t.image)
+                        .collect::<Vec<_>>(),
+                )
+                .await?;
+                let images = candidates
+                    .into_iter()
+                    .map(|t| {
+                        let mut image = t.image;
+                        if let Some(digest) = &image.id.image_digest {
+                            image.labels = labels.remove(digest).unwrap_or_default();
+                        }
+                        image
+                    })
+                    .collect();
+
+                Ok(filter.select(images, now))
             });
         });
 
         try_join_set_to_stream(tasks)
-            .try_fold(HashSet::new(), |mut acc, cur| async {
-                let ids = cur.into_iter().collect::<HashSet<_>>();
-                acc.extend(ids);
+            .try_fold(Vec::new(), |mut acc, cur| async {
+                acc.extend(cur);
                 Ok(acc)
             })
             .await
-            .map_err(ImageProviderError::from)
+    }
+
+    async fn estimate_reclaimed_bytes(
+        &self,
+        images: &ImagesSummary,
+    ) -> Result<i64, ImageProviderError> {
+        let mut total = 0;
+        for (repo, targets) in images {
+            let deleted = targets
+                .iter()
+                .filter(|t| t.action() == CleanupAction::Delete)
+                .filter_map(|t| t.image.id.image_digest.clone())
+                .collect::<HashSet<_>>();
+            if deleted.is_empty() {
+                continue;
+            }
+
+            let digests = self
+                .client
+                .describe_images()
+                .repository_name(repo)
+                .into_paginator()
+                .items()
+                .send()
+                .collect::<Result<Vec<_>, _>>()
+                .await?
+                .iter()
+                .filter_map(|d| d.image_digest().map(|s| s.to_owned()))
+                .collect::<Vec<_>>();
+            let manifests = batch_get_manifests(
+                &self.client,
+                repo,
+                &digests,
+                &[IMAGE_MANIFEST_MEDIA_TYPES, IMAGE_INDEX_MEDIA_TYPES].concat(),
+            )
+            .await?;
+
+            let manifests = manifests
+                .into_iter()
+                .map(|(digest, manifest)| {
+                    serde_json::from_str(&manifest)
+                        .map(|m| (digest, m))
+                        .map_err(ImageProviderError::manifest_error)
+                })
+                .collect::<Result<HashMap<_, _>, _>>()?;
+            total += released_bytes(manifests, &deleted);
+        }
+
+        Ok(total)
+    }
+}
+
+/// The media types of image indexes, which reference other image manifests
+const IMAGE_INDEX_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.oci.image.index.v1+json",
+];
+
+/// Decide whether the media type is of an image index
+fn is_image_index(media_type: &str) -> bool {
+    IMAGE_INDEX_MEDIA_TYPES.contains(&media_type)
+}
+
+/// The manifest of an image index. Only the required fields are declared
+#[derive(serde::Deserialize)]
+struct ImageIndexManifest {
+    manifests: Vec<ImageIndexEntry>,
+}
+
+/// An entry of an image index manifest
+#[derive(serde::Deserialize)]
+struct ImageIndexEntry {
+    digest: String,
+}
+
+/// The media types of image manifests, which reference the config and the layers
+const IMAGE_MANIFEST_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.docker.distribution.manifest.v2+json",
+    "application/vnd.oci.image.manifest.v1+json",
+];
+
+/// An image manifest. Only the blobs are needed
+#[derive(serde::Deserialize)]
+struct ImageManifest {
+    /// The image config, which is missing in image indexes
+    #[serde(default)]
+    config: Option<ManifestBlob>,
+    /// The layers, which are missing in image indexes
+    #[serde(default)]
+    layers: Vec<ManifestBlob>,
+}
+
+/// A blob referenced by an image manifest
+#[derive(serde::Deserialize)]
+struct ManifestBlob {
+    digest: String,
+    size: i64,
+}
+
+/// An image config. Only the labels are needed
+#[derive(serde::Deserialize)]
+struct ImageConfigBlob {
+    #[serde(default)]
+    config: Option<ContainerConfig>,
+}
+
+/// The container config in an image config
+#[derive(serde::Deserialize)]
+struct ContainerConfig {
+    #[serde(rename = "Labels", default)]
+    labels: Option<BTreeMap<String, String>>,
+}
+
+/// The bytes of the blobs which only the deleted manifests refer to.
+/// The blobs are released only if no kept manifest refers to them.
+fn released_bytes(manifests: HashMap<String, ImageManifest>, deleted: &HashSet<String>) -> i64 {
+    let (mut released, mut kept) = (HashMap::new(), HashSet::new());
+    for (digest, manifest) in manifests {
+        for blob in manifest.config.into_iter().chain(manifest.layers) {
+            if deleted.contains(&digest) {
+                released.insert(blob.digest, blob.size);
+            } else {
+                kept.insert(blob.digest);
+            }
+        }
+    }
+
+    released
+        .into_iter()
+        .filter(|(d, _)| !kept.contains(d))
+        .map(|(_, size)| size)
+        .sum()
+}
+
+/// Fetch the manifests of the image indexes and return their child digests keyed by the index digest
+async fn describe_child_digests(
+    client: &aws_sdk_ecr::Client,
+    repository_name: &str,
+    index_digests: &[String],
+) -> Result<HashMap<String, Vec<String>>, ImageProviderError> {
+    let manifests = batch_get_manifests(
+        client,
+        repository_name,
+        index_digests,
+        &IMAGE_INDEX_MEDIA_TYPES,
+    )
+    .await?;
+
+    let mut ret = HashMap::new();
+    for (digest, manifest) in manifests {
+        let manifest: ImageIndexManifest =
+            serde_json::from_str(&manifest).map_err(ImageProviderError::manifest_error)?;
+        ret.insert(
+            digest,
+            manifest.manifests.into_iter().map(|m| m.digest).collect(),
+        );
+    }
+
+    Ok(ret)
+}
+
+/// Download the configs of the images and return their labels keyed by the digest.
+/// An image index takes the labels of its first child which has any.
+async fn describe_labels(
+    client: &aws_sdk_ecr::Client,
+    label_client: &reqwest::Client,
+    repository_name: &str,
+    images: &[&EcrImageDetail],
+) -> Result<HashMap<String, BTreeMap<String, String>>, ImageProviderError> {
+    // image indexes have no config, so only their children are fetched
+    let digests = images
+        .iter()
+        .flat_map(|i| {
+            if i.child_digests.is_empty() {
+                i.id.image_digest.iter().collect::<Vec<_>>()
+            } else {
+                i.child_digests.iter().collect()
+            }
+        })
+        .cloned()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let manifests = batch_get_manifests(
+        client,
+        repository_name,
+        &digests,
+        &IMAGE_MANIFEST_MEDIA_TYPES,
+    )
+    .await?;
+
+    let mut ret = HashMap::new();
+    for (digest, manifest) in manifests {
+        let manifest: ImageManifest =
+            serde_json::from_str(&manifest).map_err(ImageProviderError::manifest_error)?;
+        // the labels may keep the image, so an image whose config cannot be read fails the run
+        let missing = || MissingConfigError {
+            repository_name: repository_name.to_owned(),
+            image_digest: digest.clone(),
+        };
+        let Some(config) = manifest.config else { return Err(ImageProviderError::manifest_error(missing())); };
+        let output = client
+            .get_download_url_for_layer()
+            .repository_name(repository_name)
+            .layer_digest(&config.digest)
+            .send()
+            .await?;
+        let Some(url) = output.download_url() else { return Err(ImageProviderError::download_error(missing())); };
+
+        let blob: ImageConfigBlob = label_client
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(ImageProviderError::download_error)?
+            .json()
+            .await
+            .map_err(ImageProviderError::download_error)?;
+        ret.insert(
+            digest,
+            blob.config.and_then(|c| c.labels).unwrap_or_default(),
+        );
+    }
+
+    inherit_labels(&mut ret, images);
+    Ok(ret)
+}
+
+/// Give each image index the labels of its first child which has any
+fn inherit_labels(
+    labels: &mut HashMap<String, BTreeMap<String, String>>,
+    images: &[&EcrImageDetail],
+) {
+    for image in images {
+        let Some(digest) = &image.id.image_digest else { continue; };
+        let inherited = image
+            .child_digests
+            .iter()
+            .filter_map(|d| labels.get(d))
+            .find(|l| !l.is_empty())
+            .cloned();
+        if let Some(inherited) = inherited {
+            labels.insert(digest.clone(), inherited);
+        }
+    }
+}
+
+/// An error reported when the image config of an image cannot be located
+#[derive(Debug, thiserror::Error)]
+#[error("failed to locate the image config of {repository_name}@{image_digest}")]
+struct MissingConfigError {
+    /// The repository of the image
+    repository_name: String,
+    /// The digest of the image
+    image_digest: String,
+}
+
+/// Fetch the manifests of the images and return them keyed by the digest
+async fn batch_get_manifests(
+    client: &aws_sdk_ecr::Client,
+    repository_name: &str,
+    digests: &[String],
+    media_types: &[&str],
+) -> Result<HashMap<String, String>, ImageProviderError> {
+    let mut ret = HashMap::new();
+    for chunk in digests.chunks(100) {
+        let ids = chunk
+            .iter()
+            .map(|d| ImageIdentifier::builder().image_digest(d).build())
+            .collect::<Vec<_>>();
+
+        let output = client
+            .batch_get_image()
+            .repository_name(repository_name)
+            .set_image_ids(Some(ids))
+            .set_accepted_media_types(Some(media_types.iter().map(|t| t.to_string()).collect()))
+            .send()
+            .await?;
+
+        ret.extend(collect_manifests(
+            repository_name,
+            output.images().unwrap_or_default(),
+            output.failures().unwrap_or_default(),
+        )?);
+    }
+
+    Ok(ret)
+}
+
+/// Collect the manifests returned by BatchGetImage keyed by the digest.
+/// An image which the registry failed to return is an error, as what to keep cannot be decided without it.
+fn collect_manifests(
+    repository_name: &str,
+    images: &[Image],
+    failures: &[ImageFailure],
+) -> Result<HashMap<String, String>, ImageProviderError> {
+    if let Some(failure) = failures.first() {
+        return Err(ImageProviderError::manifest_error(MissingManifestError {
+            repository_name: repository_name.to_owned(),
+            image_digest: failure
+                .image_id()
+                .and_then(|id| id.image_digest())
+                .unwrap_or_default()
+                .to_owned(),
+            reason: failure.failure_reason().unwrap_or_default().to_owned(),
+        }));
+    }
+
+    let mut ret = HashMap::new();
+    for image in images {
+        let Some(digest) = image.image_id().and_then(|id| id.image_digest()) else { continue; };
+        let Some(manifest) = image.image_manifest() else { continue; };
+        ret.insert(digest.to_owned(), manifest.to_owned());
+    }
+
+    Ok(ret)
+}
+
+/// An error reported when the registry fails to return a requested manifest
+#[derive(Debug, thiserror::Error)]
+#[error("failed to get the manifest of {repository_name}@{image_digest}: {reason}")]
+struct MissingManifestError {
+    /// The repository of the image
+    repository_name: String,
+    /// The digest of the image
+    image_digest: String,
+    /// The reason reported by the registry
+    reason: String,
+}
+
+/// The maximum number of attempts to delete an image
+const MAX_DELETE_ATTEMPTS: u64 = 3;
+
+impl EcrImageRegistry {
+    /// Delete images in the repository and return the failures
+    async fn batch_delete(
+        &self,
+        repository_name: &str,
+        ids: Vec<ImageIdentifier>,
+    ) -> Result<Vec<ImageFailure>, ImageDeleterError> {
+        let mut failures = Vec::new();
+        for chunk in ids.chunks(100).map(|chunk| chunk.to_vec()) {
+            let output = self
+                .client
+                .batch_delete_image()
+                .repository_name(repository_name)
+                .set_image_ids(Some(chunk))
+                .send()
+                .await?;
+            // the image deleted in the meantime is what is wanted
+            failures.extend(
+                output
+                    .failures()
+                    .unwrap_or_default()
+                    .iter()
+                    .filter(|f| f.failure_code() != Some(&ImageFailureCode::ImageNotFound))
+                    .cloned(),
+            );
+        }
+
+        Ok(failures)
+    }
+}
+
+/// Decide whether the deletion may succeed if retried
+fn is_retryable(failure: &ImageFailure) -> bool {
+    matches!(
+        failure.failure_code(),
+        // the image index may be deleted in the meantime
+        Some(ImageFailureCode::ImageReferencedByManifestList) | Some(ImageFailureCode::KmsError)
+    )
+}
+
+/// Convert the failure reported by the registry
+fn deletion_failure(failure: &ImageFailure) -> DeletionFailure {
+    DeletionFailure {
+        image_id: failure
+            .image_id()
+            .cloned()
+            .unwrap_or_else(|| ImageIdentifier::builder().build()),
+        code: failure
+            .failure_code()
+            .map(|c| c.as_str().to_owned())
+            .unwrap_or_default(),
+        reason: failure.failure_reason().unwrap_or_default().to_owned(),
     }
 }
 
 #[async_trait::async_trait]
 impl ImageDeleter for EcrImageRegistry {
-    async fn delete_images(&self, images: &ImagesSummary) -> Result<(), ImageDeleterError> {
-        for (repo, ids) in images {
-            for chunk in ids.chunks(100).map(|chunk| chunk.to_vec()) {
-                self.client
-                    .batch_delete_image()
+    async fn delete_images(
+        &self,
+        images: &ImagesSummary,
+    ) -> Result<DeletionFailures, ImageDeleterError> {
+        let mut ret = DeletionFailures::new();
+        for (repo, targets) in images {
+            // image indexes have to be deleted before their children
+            let (indexes, others): (Vec<_>, Vec<_>) = targets
+                .iter()
+                .partition(|t| !t.image.child_digests.is_empty());
+
+            let mut failures = Vec::new();
+            for group in [indexes, others] {
+                let ids = group
+                    .iter()
+                    .flat_map(|t| t.image_identifiers())
+                    .collect::<Vec<_>>();
+                failures.extend(self.batch_delete(repo, ids).await?);
+            }
+
+            for attempt in 1..MAX_DELETE_ATTEMPTS {
+                let (retry, rest): (Vec<_>, Vec<_>) = failures.into_iter().partition(is_retryable);
+                failures = rest;
+                if retry.is_empty() {
+                    break;
+                }
+
+                tokio::time::sleep(Duration::from_secs(attempt)).await;
+                let ids = retry
+                    .iter()
+                    .filter_map(|f| f.image_id().cloned())
+                    .collect::<Vec<_>>();
+                failures.extend(self.batch_delete(repo, ids).await?);
+            }
+
+            if !failures.is_empty() {
+                ret.insert(
+                    repo.clone(),
+                    failures.iter().map(deletion_failure).collect(),
+                );
+            }
+        }
+
+        Ok(ret)
+    }
+
+    async fn check_limits(&self, images: &ImagesSummary) -> Result<(), ImageDeleterError> {
+        let mut violations = Vec::new();
+        let (mut total_images, mut total_bytes) = (0, 0);
+
+        for (repo, targets) in images {
+            let deleted = targets
+                .iter()
+                .filter(|t| t.action() == CleanupAction::Delete)
+                .collect::<Vec<_>>();
+            if deleted.is_empty() {
+                continue;
+            }
+
+            let count = deleted.len() as u64;
+            let bytes = deleted
+                .iter()
+                .map(|t| t.image.image_size_in_bytes.max(0) as u64)
+                .sum::<u64>();
+            total_images += count;
+            total_bytes += bytes;
+
+            // the limits follow the filters applied to the repository
+            let repository_limits = self
+                .filter
+                .applicable(repo)
+                .into_iter()
+                .filter_map(|f| f.limits.as_ref())
+                .collect::<Vec<_>>();
+            let needs_total = self.limits.max_percentage.is_some()
+                || repository_limits.iter().any(|l| l.max_percentage.is_some());
+            if needs_total {
+                let total = self
+                    .client
+                    .describe_images()
                     .repository_name(repo)
-                    .set_image_ids(Some(chunk))
+                    .into_paginator()
+                    .items()
                     .send()
-                    .await?;
+                    .collect::<Result<Vec<_>, _>>()
+                    .await?
+                    .len() as u64;
+                violations.extend(self.limits.check_percentage(repo, count, total));
+                for limits in &repository_limits {
+                    violations.extend(limits.check_percentage(repo, count, total));
+                }
+            }
+
+            for limits in repository_limits {
+                violations.extend(limits.check(repo, count, bytes));
             }
         }
 
+        violations.extend(self.limits.check("registry", total_images, total_bytes));
+
+        if !violations.is_empty() {
+            return Err(ImageDeleterError::limit_exceeded_error(violations));
+        }
         Ok(())
     }
 }
 
 impl ImageRegistry for EcrImageRegistry {}
 
-/// A filter for deciding whether an image is target or not
+/// Limits of deletion in a single run
 #[cfg_attr(test, derive(Debug))]
+#[derive(Clone, Default)]
+struct DeletionLimits {
+    /// The maximum number of images to delete
+    max_images: Option<u64>,
+    /// The maximum percentage of images to delete in a repository
+    max_percentage: Option<f64>,
+    /// The maximum total size of images to delete in bytes
+    max_bytes: Option<u64>,
+}
+
+impl DeletionLimits {
+    /// Create a new DeletionLimits
+    fn new(conf: Option<&DeletionLimitsConfig>) -> Self {
+        conf.map(|c| Self {
+            max_images: c.max_images,
+            max_percentage: c.max_percentage,
+            max_bytes: c.max_bytes,
+        })
+        .unwrap_or_default()
+    }
+
+    /// Check the number and the size of images to delete in the scope
+    fn check(&self, scope: &str, count: u64, bytes: u64) -> Vec<String> {
+        let mut violations = Vec::new();
+        if let Some(max) = self.max_images.filter(|max| count > *max) {
+            violations.push(format!(
+                "{}: {} images to delete exceeds max_images {}",
+                scope, count, max
+            ));
+        }
+        if let Some(max) = self.max_bytes.filter(|max| bytes > *max) {
+            violations.push(format!(
+                "{}: {} bytes to delete exceeds max_bytes {}",
+                scope, bytes, max
+            ));
+        }
+        violations
+    }
+
+    /// Check the percentage of images to delete in the repository
+    fn check_percentage(&self, repository_name: &str, count: u64, total: u64) -> Option<String> {
+        let max = self.max_percentage?;
+        let percentage = if total == 0 {
+            100.0
+        } else {
+            count as f64 * 100.0 / total as f64
+        };
+
+        (percentage > max).then(|| {
+            format!(
+                "{}: {:.1}% of images to delete exceeds max_percentage {}",
+                repository_name, percentage, max
+            )
+        })
+    }
+}
+
+/// The prefix of the repository tags which override the filter
+const OVERRIDE_TAG_PREFIX: &str = "dyson:";
+
+/// The longest `days_after` which a repository tag may give, about 100 years
+const MAX_OVERRIDE_DAYS: u64 = 36500;
+
+/// The overrides of the filter given by the tags of a repository
+#[derive(Debug, Default, PartialEq)]
+struct RepositoryOverrides {
+    /// overrides `days_after` of the filters, but not the one of their tag rules
+    days_after: Option<u64>,
+    /// overrides `keep_latest` of the filters
+    keep_latest: Option<usize>,
+    /// whether the repository is excluded
+    exclude: bool,
+}
+
+impl RepositoryOverrides {
+    /// Parse the overrides from the tags of the repository, e.g. `dyson:days_after=14`.
+    /// Unknown keys are ignored, and invalid values are rejected.
+    fn try_from_tags(repository_name: &str, tags: &[Tag]) -> Result<Self, ImageProviderError> {
+        let mut overrides = Self::default();
+        for tag in tags {
+            let Some(key) = tag.key().and_then(|k| k.strip_prefix(OVERRIDE_TAG_PREFIX)) else { continue; };
+            let value = tag.value().unwrap_or_default();
+            let invalid = || {
+                ImageProviderError::initialization_error(InvalidOverrideError {
+                    repository_name: repository_name.to_owned(),
+                    key: key.to_owned(),
+                    value: value.to_owned(),
+                })
+            };
+
+            match key {
+                "days_after" => {
+                    let days = value.parse().map_err(|_| invalid())?;
+                    if days > MAX_OVERRIDE_DAYS {
+                        return Err(invalid());
+                    }
+                    overrides.days_after = Some(days);
+                }
+                "keep_latest" => {
+                    overrides.keep_latest = Some(value.parse().map_err(|_| invalid())?)
+                }
+                "exclude" => overrides.exclude = value.parse().map_err(|_| invalid())?,
+                _ => {}
+            }
+        }
+
+        Ok(overrides)
+    }
+}
+
+/// An error reported when a repository tag has an invalid value
+#[derive(Debug, thiserror::Error)]
+#[error("invalid value `{value}` of the tag `{OVERRIDE_TAG_PREFIX}{key}` in {repository_name}")]
+struct InvalidOverrideError {
+    repository_name: String,
+    key: String,
+    value: String,
+}
+
+/// A filter for deciding whether an image is target or not
+#[cfg_attr(test, derive(Debug, Default))]
 struct ImageFilter {
     /// Vector of filter items
     filters: Vec<ImageFilterItem>,
+    /// How to evaluate the filter items matching a repository
+    mode: FilterModeConfig,
+    /// The filter item for repositories which no filter item matches
+    default: Option<ImageFilterItem>,
 }
 
 impl ImageFilter {
     /// Create a new ImageFilter
-    fn try_new(conf: &[RepositoryFilterConfig]) -> Result<Self, ImageProviderError> {
+    fn try_new(
+        conf: &[RepositoryFilterConfig],
+        mode: FilterModeConfig,
+        default: Option<&FilterRuleConfig>,
+    ) -> Result<Self, ImageProviderError> {
         Ok(Self {
             filters: conf
                 .iter()
-                .map(ImageFilterItem::try_new)
+                .map(|f| {
+                    ImageFilterItem::try_new(f.pattern.as_str(), &f.rule).map(|item| {
+                        ImageFilterItem {
+                            limits: f.limits.as_ref().map(|l| DeletionLimits::new(Some(l))),
+                            ..item
+                        }
+                    })
+                })
                 .collect::<Result<Vec<_>, _>>()?,
+            mode,
+            default: default
+                .map(|rule| ImageFilterItem::try_new("*", rule))
+                .transpose()?,
+        })
+    }
+
+    /// The filter for the repository, with the overrides given by the repository applied
+    fn for_repository(
+        &self,
+        repository_name: &str,
+        overrides: &RepositoryOverrides,
+    ) -> Result<Self, ImageProviderError> {
+        let mut filters = self
+            .applicable(repository_name)
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        if filters.is_empty() && (overrides.days_after.is_some() || overrides.keep_latest.is_some())
+        {
+            filters.push(ImageFilterItem::try_new("*", &FilterRuleConfig::default())?);
+        }
+
+        for filter in &mut filters {
+            if let Some(days_after) = overrides.days_after {
+                filter.days_after = days_after;
+            }
+            if let Some(keep_latest) = overrides.keep_latest {
+                filter.keep_latest = Some(keep_latest);
+            }
+        }
+
+        // the applicable filters are resolved already
+        Ok(Self {
+            filters,
+            mode: FilterModeConfig::All,
+            default: None,
         })
     }
 
-    /// Decide whether the image is target or not
-    fn is_match(&self, image: &EcrImageDetail, now: SystemTime) -> bool {
-        self.filters.iter().all(|f| f.is_match(image, now))
+    /// The filter items which apply to the repository
+    fn applicable(&self, repository_name: &str) -> Vec<&ImageFilterItem> {
+        let mut matched = self
+            .filters
+            .iter()
+            .filter(|f| f.pattern.matches(repository_name));
+        let applicable = match self.mode {
+            FilterModeConfig::All => matched.collect::<Vec<_>>(),
+            FilterModeConfig::FirstMatch => matched.next().into_iter().collect(),
+        };
+
+        if applicable.is_empty() {
+            return self.default.iter().collect();
+        }
+        applicable
+    }
+
+    /// Select the target tags of the images.
+    /// The filters are evaluated per repository over all of its images.
+    fn select(&self, images: Vec<EcrImageDetail>, now: SystemTime) -> Vec<EcrImageTarget> {
+        let mut repositories: HashMap<String, Vec<EcrImageDetail>> = HashMap::new();
+        images.into_iter().for_each(|image| {
+            repositories
+                .entry(image.id.repository_name.clone())
+                .or_default()
+                .push(image)
+        });
+
+        repositories
+            .into_iter()
+            .flat_map(|(repository_name, images)| {
+                let latest = self.latest_digests(&repository_name, &images);
+                let kept_tags = self.kept_tags(&repository_name, &images);
+                images.into_iter().map(move |image| {
+                    let digest = image.id.image_digest.clone().unwrap_or_default();
+                    if latest.contains(&digest) || is_protected_by_labels(&image, now) {
+                        return EcrImageTarget::new(image, Vec::new());
+                    }
+
+                    if image.image_tags.is_empty() {
+                        if self.is_untagged_match(&image, now) {
+                            return EcrImageTarget::untagged(image);
+                        }
+                        return EcrImageTarget::new(image, Vec::new());
+                    }
+
+                    let target_tags = image
+                        .image_tags
+                        .iter()
+                        .filter(|t| self.is_match(&image, t, now))
+                        .filter(|t| !kept_tags.contains(&(digest.clone(), t.to_string())))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    EcrImageTarget::new(image, target_tags)
+                })
+            })
+            .collect()
+    }
+
+    /// The digests of the most recently pushed tagged images to keep in the repository.
+    /// If multiple filters match the repository, the largest `keep_latest` wins.
+    fn latest_digests(&self, repository_name: &str, images: &[EcrImageDetail]) -> HashSet<String> {
+        let keep_latest = self
+            .applicable(repository_name)
+            .into_iter()
+            .filter_map(|f| f.keep_latest)
+            .max()
+            .unwrap_or(0);
+
+        latest_images(
+            images.iter().filter(|i| !i.image_tags.is_empty()),
+            keep_latest,
+        )
+        .into_iter()
+        .filter_map(|i| i.id.image_digest.clone())
+        .collect()
+    }
+
+    /// The pairs of the digest and the tag to keep regardless of their age,
+    /// i.e. by `keep_latest` of the tag rules and by the semver retention
+    fn kept_tags(
+        &self,
+        repository_name: &str,
+        images: &[EcrImageDetail],
+    ) -> HashSet<(String, String)> {
+        self.applicable(repository_name)
+            .into_iter()
+            .flat_map(|f| {
+                f.latest_tags(images)
+                    .into_iter()
+                    .chain(f.release_tags(images))
+            })
+            .collect()
+    }
+
+    /// Decide whether the tag of the image is target or not
+    fn is_match(&self, image: &EcrImageDetail, tag: &str, now: SystemTime) -> bool {
+        self.applicable(&image.id.repository_name)
+            .iter()
+            .all(|f| f.is_match(image, tag, now))
+    }
+
+    /// Decide whether the untagged image is target or not.
+    /// Untagged images are target only if any filter for the repository enables them.
+    fn is_untagged_match(&self, image: &EcrImageDetail, now: SystemTime) -> bool {
+        let applicable = self.applicable(&image.id.repository_name);
+        let enabled = applicable.iter().any(|f| f.untagged_days_after.is_some());
+
+        enabled && applicable.iter().all(|f| f.is_untagged_match(image, now))
     }
 }
 
 /// a filter item of ImageFilter
-#[cfg_attr(test, derive(Debug))]
+#[derive(Clone)]
+#[cfg_attr(test, derive(Debug, Default))]
 struct ImageFilterItem {
-    /// The glob pattern for repository name
-    pattern: glob::Pattern,
+    /// The pattern for repository name
+    pattern: NamePattern,
     /// the image is target if it is elapsed this days after pushed
     days_after: u64,
-    /// The glob patterns for tag to ignore
-    ignore_tag_patterns: Vec<glob::Pattern>,
+    /// The patterns for tag to ignore
+    ignore_tag_patterns: Vec<NamePattern>,
+    /// untagged images are target if it is elapsed this days after pushed
+    untagged_days_after: Option<u64>,
+    /// the number of the most recently pushed images to keep regardless of their age
+    keep_latest: Option<usize>,
+    /// the image is target if it is elapsed this days after last pulled
+    days_since_last_pull: Option<u64>,
+    /// how to treat images which have never been pulled
+    never_pulled: NeverPulledConfig,
+    /// the rules for tags. The first rule matching a tag applies to it
+    tag_rules: Vec<TagRule>,
+    /// the retention of the newest releases by semantic versions of tags
+    semver: Option<SemverRule>,
+    /// vulnerable images are target if it is elapsed this days after pushed
+    vulnerable_days_after: Option<u64>,
+    /// the minimum severity of the findings for images to be vulnerable
+    vulnerable_severity: SeverityConfig,
+    /// the image is target if it is at least this size in bytes
+    min_size_bytes: Option<i64>,
+    /// the image is target if it is at most this size in bytes
+    max_size_bytes: Option<i64>,
+    /// the limits of deletion in the repositories which this filter applies to
+    limits: Option<DeletionLimits>,
 }
 
 impl ImageFilterItem {
     /// Create a new ImageFilterItem
-    fn try_new(conf: &RepositoryFilterConfig) -> Result<Self, ImageProviderError> {
+    fn try_new(pattern: &str, conf: &FilterRuleConfig) -> Result<Self, ImageProviderError> {
         Ok(Self {
-            pattern: glob::Pattern::new(conf.pattern.as_str())
-                .map_err(ImageProviderError::initialization_error)?,
+            pattern: NamePattern::new(pattern).map_err(ImageProviderError::initialization_error)?,
             days_after: conf.days_after.unwrap_or(0), // by default, all images are target after pushed
             ignore_tag_patterns: conf
                 .ignore_tag_patterns
@@ -190,43 +1062,335 @@ impl ImageFilterItem {
                 .unwrap_or(&Vec::new())
                 .iter()
                 .map(|p| {
-                    glob::Pattern::new(p.as_str()).map_err(ImageProviderError::initialization_error)
+                    NamePattern::new(p.as_str()).map_err(ImageProviderError::initialization_error)
                 })
                 .collect::<Result<Vec<_>, _>>()?,
+            untagged_days_after: conf.untagged.as_ref().map(|u| u.days_after.unwrap_or(0)),
+            keep_latest: conf.keep_latest.map(|n| n as usize),
+            days_since_last_pull: conf.days_since_last_pull,
+            never_pulled: conf.never_pulled.unwrap_or_default(),
+            tag_rules: conf
+                .tag_rules
+                .as_ref()
+                .unwrap_or(&Vec::new())
+                .iter()
+                .map(TagRule::try_new)
+                .collect::<Result<Vec<_>, _>>()?,
+            semver: conf.semver.as_ref().map(SemverRule::new),
+            vulnerable_days_after: conf.vulnerable.as_ref().and_then(|v| v.days_after),
+            vulnerable_severity: conf
+                .vulnerable
+                .as_ref()
+                .and_then(|v| v.severity)
+                .unwrap_or_default(),
+            min_size_bytes: conf.min_size_bytes,
+            max_size_bytes: conf.max_size_bytes,
+            limits: None,
         })
     }
 
-    /// Decide whether the image is target or not
-    pub fn is_match(&self, image: &EcrImageDetail, now: SystemTime) -> bool {
+    /// The rule which applies to the tag
+    fn tag_rule(&self, tag: &str) -> Option<&TagRule> {
+        self.tag_rule_position(tag).map(|i| &self.tag_rules[i])
+    }
+
+    /// The position of the rule which applies to the tag
+    fn tag_rule_position(&self, tag: &str) -> Option<usize> {
+        self.tag_rules.iter().position(|r| r.pattern.matches(tag))
+    }
+
+    /// The pairs of the digest and the tag to keep by `keep_latest` of the tag rules.
+    /// For each rule, the tags of the most recently pushed images having a tag the rule applies to are kept.
+    fn latest_tags(&self, images: &[EcrImageDetail]) -> HashSet<(String, String)> {
+        let mut latest = HashSet::new();
+        if !images
+            .first()
+            .is_some_and(|i| self.pattern.matches(i.id.repository_name.as_str()))
+        {
+            return latest;
+        }
+
+        for (position, rule) in self.tag_rules.iter().enumerate() {
+            let Some(keep_latest) = rule.keep_latest else { continue; };
+            let applies = |tag: &String| self.tag_rule_position(tag) == Some(position);
+            let kept = latest_images(
+                images.iter().filter(|i| i.image_tags.iter().any(applies)),
+                keep_latest,
+            );
+            for image in kept {
+                let Some(digest) = &image.id.image_digest else { continue; };
+                image
+                    .image_tags
+                    .iter()
+                    .filter(|t| applies(t))
+                    .for_each(|t| {
+                        latest.insert((digest.clone(), t.clone()));
+                    });
+            }
+        }
+
+        latest
+    }
+
+    /// The pairs of the digest and the tag to keep as the newest releases by the semver retention
+    fn release_tags(&self, images: &[EcrImageDetail]) -> HashSet<(String, String)> {
+        let Some(semver) = &self.semver else { return HashSet::new(); };
+
+        let releases = images
+            .iter()
+            .filter_map(|i| i.id.image_digest.as_ref().map(|d| (d, &i.image_tags)))
+            .flat_map(|(digest, tags)| {
+                tags.iter()
+                    .filter_map(move |t| parse_release(t).map(|v| (digest, t, v)))
+            })
+            .collect::<Vec<_>>();
+        let retained = semver.retained(releases.iter().map(|(_, _, v)| *v).collect());
+
+        releases
+            .into_iter()
+            .filter(|(_, _, v)| retained.contains(v))
+            .map(|(digest, tag, _)| (digest.clone(), tag.clone()))
+            .collect()
+    }
+
+    /// Decide whether the tag of the image is target or not
+    pub fn is_match(&self, image: &EcrImageDetail, tag: &str, now: SystemTime) -> bool {
         // if repository name not match, that means this image is target (ignore)
         if !self.pattern.matches(image.id.repository_name.as_str()) {
             return true;
         }
 
-        let n_days_before = aws_smithy_types::DateTime::from(
-            now - Duration::from_secs(self.days_after * 24 * 60 * 60),
-        );
+        // if image is pushed is newer than n_days_before, that means this image is not target.
+        // the rule for the tag takes precedence over the filter
+        let days_after = self
+            .tag_rule(tag)
+            .and_then(|r| r.days_after)
+            .unwrap_or(self.days_after);
+        if !is_pushed_before(image, self.vulnerable_days(image, days_after), now) {
+            return false;
+        }
 
-        // if image is pushed is newer than n_days_before, that means this image is not target
-        if image.image_pushed_at.as_secs_f64() > n_days_before.as_secs_f64() {
+        // if image is pulled recently, that means this image is not target
+        if !self.is_pulled_before(image, now) {
             return false;
         }
 
-        // if image tag matches ignore_tag_pattern, that means this image is not target
+        // if image size is out of range, that means this image is not target
+        if !self.is_size_in_range(image) {
+            return false;
+        }
+
+        // if image tag matches ignore_tag_pattern, that means this tag is not target
         for ignore_tag_pattern in &self.ignore_tag_patterns {
-            if ignore_tag_pattern.matches(image.id.image_tag.as_str()) {
+            if ignore_tag_pattern.matches(tag) {
                 return false;
             }
         }
 
         true
     }
+
+    /// Decide whether the untagged image is target or not
+    pub fn is_untagged_match(&self, image: &EcrImageDetail, now: SystemTime) -> bool {
+        // if repository name not match or untagged images are not configured, ignore this filter
+        if !self.pattern.matches(image.id.repository_name.as_str()) {
+            return true;
+        }
+        let Some(days_after) = self.untagged_days_after else { return true; };
+
+        is_pushed_before(image, self.vulnerable_days(image, days_after), now)
+            && self.is_pulled_before(image, now)
+            && self.is_size_in_range(image)
+    }
+
+    /// The days after which the image is target, shortened if the image is vulnerable
+    fn vulnerable_days(&self, image: &EcrImageDetail, days_after: u64) -> u64 {
+        match self.vulnerable_days_after {
+            Some(days) if image.findings_at_least(self.vulnerable_severity) > 0 => {
+                days_after.min(days)
+            }
+            _ => days_after,
+        }
+    }
+
+    /// Decide whether the size of the image is within `min_size_bytes` and `max_size_bytes`
+    fn is_size_in_range(&self, image: &EcrImageDetail) -> bool {
+        let size = image.image_size_in_bytes;
+        self.min_size_bytes.is_none_or(|min| size >= min)
+            && self.max_size_bytes.is_none_or(|max| size <= max)
+    }
+
+    /// Decide whether the image is last pulled long enough ago.
+    /// Images which have never been pulled are treated according to `never_pulled`.
+    fn is_pulled_before(&self, image: &EcrImageDetail, now: SystemTime) -> bool {
+        let Some(days) = self.days_since_last_pull else { return true; };
+
+        match (&image.last_recorded_pull_time, self.never_pulled) {
+            (Some(pulled_at), _) => is_before(pulled_at, days, now),
+            (None, NeverPulledConfig::PushedAt) => is_pushed_before(image, days, now),
+            (None, NeverPulledConfig::Target) => true,
+            (None, NeverPulledConfig::Keep) => false,
+        }
+    }
+}
+
+/// a rule for tags in ImageFilterItem
+#[derive(Clone)]
+#[cfg_attr(test, derive(Debug, Default))]
+struct TagRule {
+    /// The pattern for tag
+    pattern: NamePattern,
+    /// the tag is target if it is elapsed this days after pushed
+    days_after: Option<u64>,
+    /// the number of the most recently pushed images whose tags are kept
+    keep_latest: Option<usize>,
+}
+
+impl TagRule {
+    /// Create a new TagRule
+    fn try_new(conf: &TagRuleConfig) -> Result<Self, ImageProviderError> {
+        Ok(Self {
+            pattern: NamePattern::new(conf.pattern.as_str())
+                .map_err(ImageProviderError::initialization_error)?,
+            days_after: conf.days_after,
+            keep_latest: conf.keep_latest.map(|n| n as usize),
+        })
+    }
+}
+
+/// A release version, i.e. the major, minor and patch version
+type Release = (u64, u64, u64);
+
+/// The retention of the newest releases in ImageFilterItem
+#[derive(Clone)]
+#[cfg_attr(test, derive(Debug, Default))]
+struct SemverRule {
+    /// the number of the newest minor versions to keep for each major version
+    keep_minors: Option<usize>,
+    /// the number of the newest patch versions to keep for each minor version
+    keep_patches: Option<usize>,
+}
+
+impl SemverRule {
+    /// Create a new SemverRule
+    fn new(conf: &SemverFilterConfig) -> Self {
+        Self {
+            keep_minors: conf.keep_minors.map(|n| n as usize),
+            keep_patches: conf.keep_patches.map(|n| n as usize),
+        }
+    }
+
+    /// The releases to keep among `releases`
+    fn retained(&self, releases: BTreeSet<Release>) -> HashSet<Release> {
+        let mut lines: BTreeMap<u64, BTreeMap<u64, Vec<u64>>> = BTreeMap::new();
+        for (major, minor, patch) in releases {
+            lines
+                .entry(major)
+                .or_default()
+                .entry(minor)
+                .or_default()
+                .push(patch);
+        }
+
+        let mut retained = HashSet::new();
+        for (major, minors) in lines {
+            let minors = minors
+                .into_iter()
+                .rev()
+                .take(self.keep_minors.unwrap_or(usize::MAX));
+            for (minor, patches) in minors {
+                let patches = patches
+                    .into_iter()
+                    .rev()
+                    .take(self.keep_patches.unwrap_or(usize::MAX));
+                retained.extend(patches.map(|patch| (major, minor, patch)));
+            }
+        }
+
+        retained
+    }
+}
+
+/// Parse a tag as a release version such as `1.2.3` or `v1.2.3`.
+/// Pre-releases such as `1.2.3-rc.1` are not releases.
+fn parse_release(tag: &str) -> Option<Release> {
+    let pattern = {
+        static RE: OnceCell<Regex> = OnceCell::new();
+        RE.get_or_init(|| {
+            Regex::new(r"^v?(?P<major>\d+)\.(?P<minor>\d+)\.(?P<patch>\d+)(\+[0-9A-Za-z.-]+)?$")
+                .unwrap()
+        })
+    };
+
+    let caps = pattern.captures(tag)?;
+    Some((
+        caps.name("major")?.as_str().parse().ok()?,
+        caps.name("minor")?.as_str().parse().ok()?,
+        caps.name("patch")?.as_str().parse().ok()?,
+    ))
+}
+
+/// The `n` most recently pushed images
+fn latest_images<'a>(
+    images: impl Iterator<Item = &'a EcrImageDetail>,
+    n: usize,
+) -> Vec<&'a EcrImageDetail> {
+    let mut images = images.collect::<Vec<_>>();
+    images.sort_by_key(|i| Reverse((i.image_pushed_at.secs(), i.image_pushed_at.subsec_nanos())));
+    images.truncate(n);
+    images
+}
+
+/// Decide whether the image is pushed more than `days` days before `now`
+fn is_pushed_before(image: &EcrImageDetail, days: u64, now: SystemTime) -> bool {
+    is_before(&image.image_pushed_at, days, now)
+}
+
+/// Decide whether the date time is more than `days` days before `now`
+fn is_before(date_time: &aws_smithy_types::DateTime, days: u64, now: SystemTime) -> bool {
+    // nothing is pushed before the time which cannot be represented
+    let Some(n_days_before) = days_before(now, days) else { return false; };
+    let n_days_before = aws_smithy_types::DateTime::from(n_days_before);
+
+    date_time.as_secs_f64() <= n_days_before.as_secs_f64()
+}
+
+/// The image label which keeps the image if `true`
+const KEEP_LABEL: &str = "io.dyson.keep";
+
+/// The image label which keeps the image until the date, e.g. `2025-01-01`
+const EXPIRES_LABEL: &str = "io.dyson.expires";
+
+/// Decide whether the labels of the image keep it at `now`.
+/// An invalid expiry keeps the image so that a typo never deletes it.
+fn is_protected_by_labels(image: &EcrImageDetail, now: SystemTime) -> bool {
+    if image.labels.get(KEEP_LABEL).is_some_and(|v| v == "true") {
+        return true;
+    }
+    let Some(expires) = image.labels.get(EXPIRES_LABEL) else { return false; };
+
+    match parse_expiry(expires) {
+        Some(expires) => {
+            aws_smithy_types::DateTime::from(now).as_secs_f64() < expires.as_secs_f64()
+        }
+        None => true,
+    }
+}
+
+/// Parse an expiry given by a date such as `2025-01-01` or by a date time in RFC 3339
+fn parse_expiry(value: &str) -> Option<aws_smithy_types::DateTime> {
+    use aws_smithy_types::date_time::Format;
+    use aws_smithy_types::DateTime;
+
+    DateTime::from_str(value, Format::DateTime)
+        .or_else(|_| DateTime::from_str(&format!("{}T00:00:00Z", value), Format::DateTime))
+        .ok()
 }
 
 /// A filter for deciding whether a repository is target or not
 #[cfg_attr(test, derive(Debug))]
 struct RepositoryExcluder {
-    patterns: Vec<glob::Pattern>,
+    patterns: Vec<NamePattern>,
 }
 
 impl RepositoryExcluder {
@@ -234,7 +1398,7 @@ impl RepositoryExcluder {
     fn new(conf: &[String]) -> Result<Self, ImageProviderError> {
         let patterns = conf
             .iter()
-            .map(|p| glob::Pattern::new(p))
+            .map(|p| NamePattern::new(p))
             .collect::<Result<Vec<_>, _>>()
             .map_err(ImageProviderError::initialization_error)?;
 
@@ -251,7 +1415,8 @@ impl RepositoryExcluder {
 mod tests {
     use aws_smithy_types::date_time::Format;
     use aws_smithy_types::DateTime;
-    use glob::Pattern;
+
+    use crate::image::EcrImageId;
 
     use super::*;
 
@@ -271,171 +1436,1212 @@ mod tests {
             TestCase {
                 name: "All images will be target if no filters".to_string(),
                 excluder: None,
-                filter: ImageFilter { filters: vec![] },
+                filter: ImageFilter {
+                    filters: vec![],
+                    ..Default::default()
+                },
                 now: SystemTime::UNIX_EPOCH,
                 input: vec![EcrImageDetail::new(
+                    "registry_id",
+                    "region",
+                    "repository_name",
+                    "sha256:digest",
+                    vec!["image_tag".to_string()],
+                    DateTime::from_str("1970-01-01T00:00:00Z", Format::DateTime).unwrap(),
+                )],
+                expected: HashSet::from([EcrImageId::new(
                     "registry_id",
                     "region",
                     "repository_name",
                     "image_tag",
+                )]),
+            },
+            TestCase {
+                name: "If repository name does not match, image will be target".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("dummy-*").unwrap(),
+                            days_after: 0,
+                            ignore_tag_patterns: vec![],
+                            ..Default::default()
+                        }
+                    ],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![EcrImageDetail::new(
+                    "registry_id",
+                    "region",
+                    "repository_name",
+                    "sha256:digest",
+                    vec!["image_tag".to_string()],
                     DateTime::from_str("1970-01-01T00:00:00Z", Format::DateTime).unwrap(),
                 )],
                 expected: HashSet::from([EcrImageId::new(
                     "registry_id",
                     "region",
-                    "repository_name",
-                    "image_tag",
-                )]),
+                    "repository_name",
+                    "image_tag",
+                )]),
+            },
+            TestCase {
+                name: "Repository name is matched, but the image is too new to be deleted".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 30,
+                            ignore_tag_patterns: vec![],
+                            ..Default::default()
+                        }
+                    ],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![EcrImageDetail::new(
+                    "registry_id",
+                    "region",
+                    "match-2",
+                    "sha256:digest",
+                    vec!["image_tag".to_string()],
+                    DateTime::from_str("1969-12-03T00:00:00Z", Format::DateTime).unwrap(),
+                )],
+                expected: Default::default(),
+            },
+            TestCase {
+                name: "Repository name is matched, and the image is old enough to be deleted".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 30,
+                            ignore_tag_patterns: vec![],
+                            ..Default::default()
+                        }
+                    ],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![EcrImageDetail::new(
+                    "registry_id",
+                    "region",
+                    "match-2",
+                    "sha256:digest",
+                    vec!["image_tag".to_string()],
+                    // UNIX_EPOCH - 31 days. this image is old enough to be deleted
+                    DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
+                )],
+                expected: HashSet::from([EcrImageId::new(
+                    "registry_id",
+                    "region",
+                    "match-2",
+                    "image_tag",
+                )]),
+            },
+            TestCase {
+                name: "Repository name is matched, and the image is old enough to be deleted, but the tag matches ignore_tag_pattern".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 30,
+                            ignore_tag_patterns: vec![
+                                NamePattern::new("ignore1-*").unwrap(),
+                                NamePattern::new("ignore2-*").unwrap(),
+                            ],
+                            ..Default::default()
+                        }
+                    ],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![EcrImageDetail::new(
+                    "registry_id",
+                    "region",
+                    "match-2",
+                    "sha256:digest",
+                    // this tag matches ignore_tag_pattern
+                    vec!["ignore2-tag".to_string()],
+                    // UNIX_EPOCH - 31 days. this image is old enough to be deleted
+                    DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
+                )],
+                expected: Default::default(),
+            },
+            TestCase {
+                name: "If repository name matches multiple filters, images will be target if all filter matches".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 50,
+                            ignore_tag_patterns: vec![],
+                            ..Default::default()
+                        },
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 30,
+                            ignore_tag_patterns: vec![],
+                            ..Default::default()
+                        },
+                    ],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![EcrImageDetail::new(
+                    "registry_id",
+                    "region",
+                    "match-1",
+                    "sha256:digest",
+                    // this tag matches ignore_tag_pattern
+                    vec!["ignore2-tag".to_string()],
+                    // UNIX_EPOCH - 31 days. this image matches one filter, but not all filters
+                    DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
+                )],
+                expected: Default::default(),
+            },
+            TestCase {
+                name: "Only the tags not matching ignore_tag_pattern will be target".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 30,
+                            ignore_tag_patterns: vec![NamePattern::new("latest").unwrap()],
+                            ..Default::default()
+                        }
+                    ],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![EcrImageDetail::new(
+                    "registry_id",
+                    "region",
+                    "match-2",
+                    "sha256:digest",
+                    vec!["v1.2".to_string(), "latest".to_string()],
+                    // UNIX_EPOCH - 31 days. this image is old enough to be deleted
+                    DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
+                )],
+                expected: HashSet::from([EcrImageId::new(
+                    "registry_id",
+                    "region",
+                    "match-2",
+                    "v1.2",
+                )]),
+            },
+            TestCase {
+                name: "Untagged images are not target unless enabled".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 30,
+                            ignore_tag_patterns: vec![],
+                            ..Default::default()
+                        }
+                    ],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![EcrImageDetail::new(
+                    "registry_id",
+                    "region",
+                    "match-2",
+                    "sha256:digest",
+                    vec![],
+                    // UNIX_EPOCH - 31 days. this image is old enough to be deleted
+                    DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
+                )],
+                expected: Default::default(),
             },
             TestCase {
-                name: "If repository name does not match, image will be target".to_string(),
+                name: "Untagged images are target if enabled and old enough".to_string(),
                 excluder: None,
                 filter: ImageFilter {
                     filters: vec![
                         ImageFilterItem {
-                            pattern: Pattern::new("dummy-*").unwrap(),
-                            days_after: 0,
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 30,
                             ignore_tag_patterns: vec![],
+                            untagged_days_after: Some(1),
+                            ..Default::default()
                         }
-                    ]
+                    ],
+                    ..Default::default()
                 },
                 now: SystemTime::UNIX_EPOCH,
                 input: vec![EcrImageDetail::new(
                     "registry_id",
                     "region",
-                    "repository_name",
-                    "image_tag",
-                    DateTime::from_str("1970-01-01T00:00:00Z", Format::DateTime).unwrap(),
+                    "match-2",
+                    "sha256:digest",
+                    vec![],
+                    // UNIX_EPOCH - 2 days
+                    DateTime::from_str("1969-12-30T00:00:00Z", Format::DateTime).unwrap(),
                 )],
-                expected: HashSet::from([EcrImageId::new(
-                    "registry_id",
-                    "region",
-                    "repository_name",
-                    "image_tag",
-                )]),
+                expected: HashSet::from([EcrImageId {
+                    registry_id: "registry_id".to_string(),
+                    region: "region".to_string(),
+                    repository_name: "match-2".to_string(),
+                    image_tag: None,
+                    image_digest: Some("sha256:digest".to_string()),
+                }]),
             },
             TestCase {
-                name: "Repository name is matched, but the image is too new to be deleted".to_string(),
+                name: "Untagged images are not target if too new".to_string(),
                 excluder: None,
                 filter: ImageFilter {
                     filters: vec![
                         ImageFilterItem {
-                            pattern: Pattern::new("match-*").unwrap(),
+                            pattern: NamePattern::new("match-*").unwrap(),
                             days_after: 30,
                             ignore_tag_patterns: vec![],
+                            untagged_days_after: Some(7),
+                            ..Default::default()
                         }
-                    ]
+                    ],
+                    ..Default::default()
                 },
                 now: SystemTime::UNIX_EPOCH,
                 input: vec![EcrImageDetail::new(
                     "registry_id",
                     "region",
                     "match-2",
-                    "image_tag",
-                    DateTime::from_str("1969-12-03T00:00:00Z", Format::DateTime).unwrap(),
+                    "sha256:digest",
+                    vec![],
+                    // UNIX_EPOCH - 2 days
+                    DateTime::from_str("1969-12-30T00:00:00Z", Format::DateTime).unwrap(),
                 )],
                 expected: Default::default(),
             },
             TestCase {
-                name: "Repository name is matched, and the image is old enough to be deleted".to_string(),
+                name: "The latest images are kept even if they are old enough to be deleted".to_string(),
                 excluder: None,
                 filter: ImageFilter {
                     filters: vec![
                         ImageFilterItem {
-                            pattern: Pattern::new("match-*").unwrap(),
+                            pattern: NamePattern::new("match-*").unwrap(),
                             days_after: 30,
                             ignore_tag_patterns: vec![],
+                            keep_latest: Some(2),
+                            ..Default::default()
                         }
-                    ]
+                    ],
+                    ..Default::default()
                 },
                 now: SystemTime::UNIX_EPOCH,
-                input: vec![EcrImageDetail::new(
-                    "registry_id",
-                    "region",
-                    "match-2",
-                    "image_tag",
-                    // UNIX_EPOCH - 31 days. this image is old enough to be deleted
-                    DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
-                )],
+                input: vec![
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest1",
+                        vec!["v1".to_string()],
+                        DateTime::from_str("1969-11-01T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest2",
+                        vec!["v2".to_string()],
+                        DateTime::from_str("1969-11-15T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    // untagged images are not counted as the latest ones
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest3",
+                        vec![],
+                        DateTime::from_str("1969-11-20T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest4",
+                        vec!["v3".to_string()],
+                        DateTime::from_str("1969-11-30T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    // the latest image in another repository does not count
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-3",
+                        "sha256:digest5",
+                        vec!["v4".to_string()],
+                        DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                ],
                 expected: HashSet::from([EcrImageId::new(
                     "registry_id",
                     "region",
                     "match-2",
-                    "image_tag",
+                    "v1",
                 )]),
             },
             TestCase {
-                name: "Repository name is matched, and the image is old enough to be deleted, but the tag matches ignore_tag_pattern".to_string(),
+                name: "The largest keep_latest wins if multiple filters match".to_string(),
                 excluder: None,
                 filter: ImageFilter {
                     filters: vec![
                         ImageFilterItem {
-                            pattern: Pattern::new("match-*").unwrap(),
+                            pattern: NamePattern::new("match-*").unwrap(),
                             days_after: 30,
-                            ignore_tag_patterns: vec![
-                                Pattern::new("ignore1-*").unwrap(),
-                                Pattern::new("ignore2-*").unwrap(),
+                            ignore_tag_patterns: vec![],
+                            keep_latest: Some(1),
+                            ..Default::default()
+                        },
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-2").unwrap(),
+                            days_after: 30,
+                            ignore_tag_patterns: vec![],
+                            keep_latest: Some(2),
+                            ..Default::default()
+                        },
+                    ],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest1",
+                        vec!["v1".to_string()],
+                        DateTime::from_str("1969-11-01T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest2",
+                        vec!["v2".to_string()],
+                        DateTime::from_str("1969-11-15T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                ],
+                expected: Default::default(),
+            },
+            TestCase {
+                name: "Images pulled recently are not target even if pushed long ago".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 0,
+                            ignore_tag_patterns: vec![],
+                            days_since_last_pull: Some(30),
+                            ..Default::default()
+                        }
+                    ],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![
+                    // UNIX_EPOCH - 2 days since the last pull
+                    EcrImageDetail {
+                        last_recorded_pull_time: Some(
+                            DateTime::from_str("1969-12-30T00:00:00Z", Format::DateTime).unwrap(),
+                        ),
+                        ..EcrImageDetail::new(
+                            "registry_id",
+                            "region",
+                            "match-2",
+                            "sha256:digest1",
+                            vec!["v1".to_string()],
+                            DateTime::from_str("1969-01-01T00:00:00Z", Format::DateTime).unwrap(),
+                        )
+                    },
+                    // UNIX_EPOCH - 31 days since the last pull
+                    EcrImageDetail {
+                        last_recorded_pull_time: Some(
+                            DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
+                        ),
+                        ..EcrImageDetail::new(
+                            "registry_id",
+                            "region",
+                            "match-2",
+                            "sha256:digest2",
+                            vec!["v2".to_string()],
+                            DateTime::from_str("1969-01-01T00:00:00Z", Format::DateTime).unwrap(),
+                        )
+                    },
+                ],
+                expected: HashSet::from([
+                    EcrImageId::new("registry_id", "region", "match-2", "v2"),
+                ]),
+            },
+            TestCase {
+                name: "Images never pulled are judged by the push date by default".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 0,
+                            ignore_tag_patterns: vec![],
+                            days_since_last_pull: Some(30),
+                            ..Default::default()
+                        }
+                    ],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest1",
+                        vec!["v1".to_string()],
+                        DateTime::from_str("1969-12-30T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest2",
+                        vec!["v2".to_string()],
+                        DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                ],
+                expected: HashSet::from([
+                    EcrImageId::new("registry_id", "region", "match-2", "v2"),
+                ]),
+            },
+            TestCase {
+                name: "Images never pulled are target if never_pulled is target".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 0,
+                            ignore_tag_patterns: vec![],
+                            days_since_last_pull: Some(30),
+                            never_pulled: NeverPulledConfig::Target,
+                            ..Default::default()
+                        }
+                    ],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest1",
+                        vec!["v1".to_string()],
+                        DateTime::from_str("1969-12-30T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                ],
+                expected: HashSet::from([
+                    EcrImageId::new("registry_id", "region", "match-2", "v1"),
+                ]),
+            },
+            TestCase {
+                name: "Images never pulled are kept if never_pulled is keep".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 0,
+                            ignore_tag_patterns: vec![],
+                            days_since_last_pull: Some(30),
+                            never_pulled: NeverPulledConfig::Keep,
+                            ..Default::default()
+                        }
+                    ],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest1",
+                        vec!["v1".to_string()],
+                        DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                ],
+                expected: Default::default(),
+            },
+            TestCase {
+                name: "The first tag rule matching a tag decides its age and keep count".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 60,
+                            ignore_tag_patterns: vec![],
+                            tag_rules: vec![
+                                TagRule {
+                                    pattern: NamePattern::new("pr-*").unwrap(),
+                                    days_after: Some(3),
+                                    ..Default::default()
+                                },
+                                TagRule {
+                                    pattern: NamePattern::new("main-*").unwrap(),
+                                    days_after: Some(30),
+                                    keep_latest: Some(1),
+                                },
+                                TagRule {
+                                    pattern: NamePattern::new("*").unwrap(),
+                                    days_after: Some(1),
+                                    ..Default::default()
+                                },
                             ],
+                            ..Default::default()
                         }
-                    ]
+                    ],
+                    ..Default::default()
                 },
                 now: SystemTime::UNIX_EPOCH,
-                input: vec![EcrImageDetail::new(
+                input: vec![
+                    // UNIX_EPOCH - 5 days. older than the rule for pr-*
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest1",
+                        vec!["pr-1".to_string()],
+                        DateTime::from_str("1969-12-27T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    // UNIX_EPOCH - 2 days. newer than the rule for pr-*
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest2",
+                        vec!["pr-2".to_string()],
+                        DateTime::from_str("1969-12-30T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    // UNIX_EPOCH - 40 days. older than the rule for main-*
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest3",
+                        vec!["main-1".to_string()],
+                        DateTime::from_str("1969-11-22T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    // UNIX_EPOCH - 35 days. kept as the latest for main-*
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest4",
+                        vec!["main-2".to_string()],
+                        DateTime::from_str("1969-11-27T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    // UNIX_EPOCH - 40 days. the first matching rule is the last one
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest5",
+                        vec!["v1".to_string()],
+                        DateTime::from_str("1969-11-22T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                ],
+                expected: HashSet::from([
+                    EcrImageId::new("registry_id", "region", "match-2", "pr-1"),
+                    EcrImageId::new("registry_id", "region", "match-2", "main-1"),
+                    EcrImageId::new("registry_id", "region", "match-2", "v1"),
+                ]),
+            },
+            TestCase {
+                name: "Tags without a matching tag rule fall back to days_after of the filter".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 60,
+                            ignore_tag_patterns: vec![],
+                            tag_rules: vec![TagRule {
+                                pattern: NamePattern::new("pr-*").unwrap(),
+                                days_after: Some(3),
+                                ..Default::default()
+                            }],
+                            ..Default::default()
+                        }
+                    ],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![
+                    // UNIX_EPOCH - 40 days. newer than days_after of the filter
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest1",
+                        vec!["v1".to_string()],
+                        DateTime::from_str("1969-11-22T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    // UNIX_EPOCH - 92 days. older than days_after of the filter
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest2",
+                        vec!["v2".to_string()],
+                        DateTime::from_str("1969-10-01T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                ],
+                expected: HashSet::from([EcrImageId::new(
                     "registry_id",
                     "region",
                     "match-2",
-                    // this tag matches ignore_tag_pattern
-                    "ignore2-tag",
-                    // UNIX_EPOCH - 31 days. this image is old enough to be deleted
-                    DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
-                )],
+                    "v2",
+                )]),
+            },
+            TestCase {
+                name: "In all mode, all the filters matching the repository must agree".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("team-a/*").unwrap(),
+                            days_after: 7,
+                            ..Default::default()
+                        },
+                        ImageFilterItem {
+                            pattern: NamePattern::new("team-*").unwrap(),
+                            days_after: 60,
+                            ..Default::default()
+                        },
+                    ],
+                    mode: FilterModeConfig::All,
+                    default: None,
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![
+                    // UNIX_EPOCH - 10 days
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "team-a/app",
+                        "sha256:digest-v1",
+                        vec!["v1".to_string()],
+                        DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    // UNIX_EPOCH - 10 days
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "team-b/app",
+                        "sha256:digest-v2",
+                        vec!["v2".to_string()],
+                        DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    // UNIX_EPOCH - 10 days. no filter matches the repository
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "other/app",
+                        "sha256:digest-v3",
+                        vec!["v3".to_string()],
+                        DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                ],
+                expected: HashSet::from([
+                    EcrImageId::new("registry_id", "region", "other/app", "v3"),
+                ]),
+            },
+            TestCase {
+                name: "In first_match mode, the first filter matching the repository decides".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("team-a/*").unwrap(),
+                            days_after: 7,
+                            ..Default::default()
+                        },
+                        ImageFilterItem {
+                            pattern: NamePattern::new("team-*").unwrap(),
+                            days_after: 60,
+                            ..Default::default()
+                        },
+                    ],
+                    mode: FilterModeConfig::FirstMatch,
+                    default: None,
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![
+                    // UNIX_EPOCH - 10 days
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "team-a/app",
+                        "sha256:digest-v1",
+                        vec!["v1".to_string()],
+                        DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    // UNIX_EPOCH - 10 days
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "team-b/app",
+                        "sha256:digest-v2",
+                        vec!["v2".to_string()],
+                        DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    // UNIX_EPOCH - 10 days. no filter matches the repository
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "other/app",
+                        "sha256:digest-v3",
+                        vec!["v3".to_string()],
+                        DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                ],
+                expected: HashSet::from([
+                    EcrImageId::new("registry_id", "region", "team-a/app", "v1"),
+                    EcrImageId::new("registry_id", "region", "other/app", "v3"),
+                ]),
+            },
+            TestCase {
+                name: "In all mode, the default filter applies to the repositories no filter matches".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("team-a/*").unwrap(),
+                            days_after: 7,
+                            ..Default::default()
+                        },
+                        ImageFilterItem {
+                            pattern: NamePattern::new("team-*").unwrap(),
+                            days_after: 60,
+                            ..Default::default()
+                        },
+                    ],
+                    mode: FilterModeConfig::All,
+                    default: Some(ImageFilterItem {
+                        pattern: NamePattern::new("*").unwrap(),
+                        days_after: 60,
+                        ..Default::default()
+                    }),
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![
+                    // UNIX_EPOCH - 10 days
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "team-a/app",
+                        "sha256:digest-v1",
+                        vec!["v1".to_string()],
+                        DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    // UNIX_EPOCH - 10 days
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "team-b/app",
+                        "sha256:digest-v2",
+                        vec!["v2".to_string()],
+                        DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    // UNIX_EPOCH - 10 days. no filter matches the repository
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "other/app",
+                        "sha256:digest-v3",
+                        vec!["v3".to_string()],
+                        DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                ],
                 expected: Default::default(),
             },
             TestCase {
-                name: "If repository name matches multiple filters, images will be target if all filter matches".to_string(),
+                name: "In first_match mode, the default filter applies to the repositories no filter matches".to_string(),
                 excluder: None,
                 filter: ImageFilter {
                     filters: vec![
                         ImageFilterItem {
-                            pattern: Pattern::new("match-*").unwrap(),
-                            days_after: 50,
-                            ignore_tag_patterns: vec![],
+                            pattern: NamePattern::new("team-a/*").unwrap(),
+                            days_after: 7,
+                            ..Default::default()
                         },
                         ImageFilterItem {
-                            pattern: Pattern::new("match-*").unwrap(),
-                            days_after: 30,
-                            ignore_tag_patterns: vec![],
+                            pattern: NamePattern::new("team-*").unwrap(),
+                            days_after: 60,
+                            ..Default::default()
                         },
-                    ]
+                    ],
+                    mode: FilterModeConfig::FirstMatch,
+                    default: Some(ImageFilterItem {
+                        pattern: NamePattern::new("*").unwrap(),
+                        days_after: 60,
+                        ..Default::default()
+                    }),
                 },
                 now: SystemTime::UNIX_EPOCH,
-                input: vec![EcrImageDetail::new(
+                input: vec![
+                    // UNIX_EPOCH - 10 days
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "team-a/app",
+                        "sha256:digest-v1",
+                        vec!["v1".to_string()],
+                        DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    // UNIX_EPOCH - 10 days
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "team-b/app",
+                        "sha256:digest-v2",
+                        vec!["v2".to_string()],
+                        DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    // UNIX_EPOCH - 10 days. no filter matches the repository
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "other/app",
+                        "sha256:digest-v3",
+                        vec!["v3".to_string()],
+                        DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                ],
+                expected: HashSet::from([
+                    EcrImageId::new("registry_id", "region", "team-a/app", "v1"),
+                ]),
+            },
+            TestCase {
+                name: "The newest releases are kept regardless of the push order".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 30,
+                            semver: Some(SemverRule {
+                                keep_minors: Some(2),
+                                keep_patches: Some(2),
+                            }),
+                            ..Default::default()
+                        }
+                    ],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest0",
+                        vec!["1.0.0".to_string()],
+                        DateTime::from_str("1969-01-01T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest1",
+                        vec!["1.2.0".to_string()],
+                        DateTime::from_str("1969-01-02T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest2",
+                        vec!["1.2.2".to_string()],
+                        DateTime::from_str("1969-01-03T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest3",
+                        vec!["1.1.0".to_string()],
+                        DateTime::from_str("1969-01-04T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest4",
+                        vec!["1.1.1".to_string()],
+                        DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest5",
+                        vec!["1.2.1".to_string()],
+                        DateTime::from_str("1969-01-05T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest6",
+                        vec!["2.0.0".to_string()],
+                        DateTime::from_str("1969-01-06T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest7",
+                        vec!["v2.0.1-rc.1".to_string()],
+                        DateTime::from_str("1969-01-07T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                    EcrImageDetail::new(
+                        "registry_id",
+                        "region",
+                        "match-2",
+                        "sha256:digest8",
+                        vec!["main".to_string()],
+                        DateTime::from_str("1969-01-08T00:00:00Z", Format::DateTime).unwrap(),
+                    ),
+                ],
+                expected: HashSet::from([
+                    EcrImageId::new("registry_id", "region", "match-2", "1.0.0"),
+                    EcrImageId::new("registry_id", "region", "match-2", "1.2.0"),
+                    EcrImageId::new("registry_id", "region", "match-2", "v2.0.1-rc.1"),
+                    EcrImageId::new("registry_id", "region", "match-2", "main"),
+                ]),
+            },
+            TestCase {
+                name: "Only the images within the size range are target".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 30,
+                            min_size_bytes: Some(1000),
+                            max_size_bytes: Some(5000),
+                            ..Default::default()
+                        }
+                    ],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![
+                    EcrImageDetail {
+                        image_size_in_bytes: 100,
+                        ..EcrImageDetail::new(
+                            "registry_id",
+                            "region",
+                            "match-2",
+                            "sha256:digest0",
+                            vec!["small".to_string()],
+                            DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
+                        )
+                    },
+                    EcrImageDetail {
+                        image_size_in_bytes: 1000,
+                        ..EcrImageDetail::new(
+                            "registry_id",
+                            "region",
+                            "match-2",
+                            "sha256:digest1",
+                            vec!["medium".to_string()],
+                            DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
+                        )
+                    },
+                    EcrImageDetail {
+                        image_size_in_bytes: 10000,
+                        ..EcrImageDetail::new(
+                            "registry_id",
+                            "region",
+                            "match-2",
+                            "sha256:digest2",
+                            vec!["large".to_string()],
+                            DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
+                        )
+                    },
+                ],
+                expected: HashSet::from([EcrImageId::new(
                     "registry_id",
                     "region",
-                    "match-1",
-                    // this tag matches ignore_tag_pattern
-                    "ignore2-tag",
-                    // UNIX_EPOCH - 31 days. this image matches one filter, but not all filters
-                    DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
-                )],
-                expected: Default::default(),
+                    "match-2",
+                    "medium",
+                )]),
+            },
+            TestCase {
+                name: "Vulnerable images are target after the shorter days".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![
+                        ImageFilterItem {
+                            pattern: NamePattern::new("match-*").unwrap(),
+                            days_after: 30,
+                            vulnerable_days_after: Some(7),
+                            vulnerable_severity: SeverityConfig::Critical,
+                            ..Default::default()
+                        }
+                    ],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![
+                    EcrImageDetail {
+                        finding_severity_counts: BTreeMap::from([("CRITICAL".to_string(), 1), ("LOW".to_string(), 3)]),
+                        ..EcrImageDetail::new(
+                            "registry_id",
+                            "region",
+                            "match-2",
+                            "sha256:digest0",
+                            vec!["critical".to_string()],
+                            // UNIX_EPOCH - 10 days
+                            DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                        )
+                    },
+                    EcrImageDetail {
+                        finding_severity_counts: BTreeMap::from([("HIGH".to_string(), 2)]),
+                        ..EcrImageDetail::new(
+                            "registry_id",
+                            "region",
+                            "match-2",
+                            "sha256:digest1",
+                            vec!["high".to_string()],
+                            // UNIX_EPOCH - 10 days
+                            DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                        )
+                    },
+                    EcrImageDetail {
+                        finding_severity_counts: BTreeMap::from([]),
+                        ..EcrImageDetail::new(
+                            "registry_id",
+                            "region",
+                            "match-2",
+                            "sha256:digest2",
+                            vec!["clean".to_string()],
+                            // UNIX_EPOCH - 10 days
+                            DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                        )
+                    },
+                ],
+                expected: HashSet::from([EcrImageId::new(
+                    "registry_id",
+                    "region",
+                    "match-2",
+                    "critical",
+                )]),
+            },
+            TestCase {
+                name: "Images are kept by their labels".to_string(),
+                excluder: None,
+                filter: ImageFilter {
+                    filters: vec![],
+                    ..Default::default()
+                },
+                now: SystemTime::UNIX_EPOCH,
+                input: vec![
+                    EcrImageDetail {
+                        labels: BTreeMap::from([("io.dyson.keep".to_string(), "true".to_string())]),
+                        ..EcrImageDetail::new(
+                            "registry_id",
+                            "region",
+                            "match-2",
+                            "sha256:digest0",
+                            vec!["keep".to_string()],
+                            // UNIX_EPOCH - 10 days
+                            DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                        )
+                    },
+                    EcrImageDetail {
+                        labels: BTreeMap::from([("io.dyson.expires".to_string(), "1970-01-02".to_string())]),
+                        ..EcrImageDetail::new(
+                            "registry_id",
+                            "region",
+                            "match-2",
+                            "sha256:digest1",
+                            vec!["not-expired".to_string()],
+                            // UNIX_EPOCH - 10 days
+                            DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                        )
+                    },
+                    EcrImageDetail {
+                        labels: BTreeMap::from([("io.dyson.expires".to_string(), "1969-12-31T12:00:00Z".to_string())]),
+                        ..EcrImageDetail::new(
+                            "registry_id",
+                            "region",
+                            "match-2",
+                            "sha256:digest2",
+                            vec!["expired".to_string()],
+                            // UNIX_EPOCH - 10 days
+                            DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                        )
+                    },
+                    EcrImageDetail {
+                        labels: BTreeMap::from([("io.dyson.expires".to_string(), "someday".to_string())]),
+                        ..EcrImageDetail::new(
+                            "registry_id",
+                            "region",
+                            "match-2",
+                            "sha256:digest3",
+                            vec!["invalid".to_string()],
+                            // UNIX_EPOCH - 10 days
+                            DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                        )
+                    },
+                    EcrImageDetail {
+                        labels: BTreeMap::from([("io.dyson.keep".to_string(), "false".to_string())]),
+                        ..EcrImageDetail::new(
+                            "registry_id",
+                            "region",
+                            "match-2",
+                            "sha256:digest4",
+                            vec!["unlabeled".to_string()],
+                            // UNIX_EPOCH - 10 days
+                            DateTime::from_str("1969-12-22T00:00:00Z", Format::DateTime).unwrap(),
+                        )
+                    },
+                ],
+                expected: HashSet::from([
+                    EcrImageId::new("registry_id", "region", "match-2", "expired"),
+                    EcrImageId::new("registry_id", "region", "match-2", "unlabeled"),
+                ]),
             },
             TestCase {
                 name: "Repository is excluded by excluder".to_string(),
                 excluder: Some(
                     RepositoryExcluder {
-                        patterns: vec![Pattern::new("match-*").unwrap()],
+                        patterns: vec![NamePattern::new("match-*").unwrap()],
                     }
                 ),
                 filter: ImageFilter {
-                    filters: vec![]
+                    filters: vec![],
+                    ..Default::default()
                 },
                 now: SystemTime::UNIX_EPOCH,
                 input: vec![EcrImageDetail::new(
                     "registry_id",
                     "region",
                     "match-2",
-                    "image_tag",
+                    "sha256:digest",
+                    vec!["image_tag".to_string()],
                     // UNIX_EPOCH - 31 days. this image is old enough to be deleted
                     DateTime::from_str("1969-12-01T00:00:00Z", Format::DateTime).unwrap(),
                 )],
@@ -453,11 +2659,285 @@ mod tests {
                     }
                     true
                 })
-                .filter(|image| case.filter.is_match(image, case.now))
-                .map(|image| image.id)
+                .collect::<Vec<_>>();
+            let actual = case
+                .filter
+                .select(actual, case.now)
+                .into_iter()
+                .flat_map(|target| {
+                    if target.untagged {
+                        return vec![target.image.id.clone()];
+                    }
+                    target
+                        .target_tags
+                        .iter()
+                        .map(|tag| target.image.tag_id(tag))
+                        .collect::<Vec<_>>()
+                })
                 .collect::<HashSet<_>>();
 
             assert_eq!(actual, case.expected, "{}", case.name);
         }
     }
+
+    #[test]
+    fn repository_overrides() {
+        let tag = |k: &str, v: &str| Tag::builder().key(k).value(v).build();
+
+        let cases = vec![
+            (vec![], Some(RepositoryOverrides::default())),
+            (
+                vec![
+                    tag("dyson:days_after", "14"),
+                    tag("dyson:keep_latest", "5"),
+                    tag("team", "a"),
+                ],
+                Some(RepositoryOverrides {
+                    days_after: Some(14),
+                    keep_latest: Some(5),
+                    exclude: false,
+                }),
+            ),
+            (
+                vec![tag("dyson:exclude", "true")],
+                Some(RepositoryOverrides {
+                    exclude: true,
+                    ..Default::default()
+                }),
+            ),
+            (vec![tag("dyson:exclude", "yes")], None),
+            (vec![tag("dyson:days_after", "-1")], None),
+            (
+                vec![tag("dyson:days_after", "36500")],
+                Some(RepositoryOverrides {
+                    days_after: Some(36500),
+                    ..Default::default()
+                }),
+            ),
+            (vec![tag("dyson:days_after", "200000000000000")], None),
+        ];
+
+        for (tags, expected) in cases {
+            let actual = RepositoryOverrides::try_from_tags("repo", &tags).ok();
+            assert_eq!(actual, expected, "{:?}", tags);
+        }
+
+        // a lifetime beyond the representable time keeps the image
+        let pushed_at = DateTime::from_secs(0);
+        assert!(!is_before(&pushed_at, u64::MAX, SystemTime::now()));
+        assert!(!is_before(&pushed_at, 200000000000000, SystemTime::now()));
+
+        let filter = ImageFilter {
+            filters: vec![ImageFilterItem {
+                pattern: NamePattern::new("match-*").unwrap(),
+                days_after: 30,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let overrides = RepositoryOverrides {
+            days_after: Some(14),
+            keep_latest: Some(5),
+            exclude: false,
+        };
+
+        let overridden = filter.for_repository("match-1", &overrides).unwrap();
+        assert_eq!(overridden.filters.len(), 1);
+        assert_eq!(overridden.filters[0].days_after, 14);
+        assert_eq!(overridden.filters[0].keep_latest, Some(5));
+
+        // the overrides apply even if no filter matches the repository
+        let overridden = filter.for_repository("other", &overrides).unwrap();
+        assert_eq!(overridden.filters.len(), 1);
+        assert_eq!(overridden.filters[0].days_after, 14);
+
+        let overridden = filter
+            .for_repository("other", &RepositoryOverrides::default())
+            .unwrap();
+        assert!(overridden.filters.is_empty());
+    }
+
+    #[test]
+    fn layer_estimate() {
+        let manifest = |config: &str, layers: &[(&str, i64)]| -> ImageManifest {
+            let layers = layers
+                .iter()
+                .map(|(digest, size)| serde_json::json!({ "digest": digest, "size": size }))
+                .collect::<Vec<_>>();
+            serde_json::from_value(serde_json::json!({
+                "config": { "digest": config, "size": 1 },
+                "layers": layers,
+            }))
+            .unwrap()
+        };
+        let manifests = HashMap::from([
+            // deleted, sharing the base layer with the kept image
+            (
+                "sha256:a".to_string(),
+                manifest(
+                    "sha256:config-a",
+                    &[("sha256:base", 100), ("sha256:app-a", 10)],
+                ),
+            ),
+            // deleted, sharing the app layer with the other deleted image
+            (
+                "sha256:b".to_string(),
+                manifest(
+                    "sha256:config-b",
+                    &[
+                        ("sha256:other", 1000),
+                        ("sha256:app-a", 10),
+                        ("sha256:app-b", 20),
+                    ],
+                ),
+            ),
+            // kept
+            (
+                "sha256:c".to_string(),
+                manifest("sha256:config-c", &[("sha256:base", 100)]),
+            ),
+            // image indexes have no blobs
+            (
+                "sha256:d".to_string(),
+                serde_json::from_str(r#"{"manifests":[{"digest":"sha256:a"}]}"#).unwrap(),
+            ),
+        ]);
+        let deleted = HashSet::from(["sha256:a".to_string(), "sha256:b".to_string()]);
+
+        // app-a, app-b, other and the configs of a and b. the base layer is kept
+        assert_eq!(released_bytes(manifests, &deleted), 10 + 20 + 1000 + 1 + 1);
+    }
+
+    #[test]
+    fn index_labels() {
+        let image = |digest: &str, children: &[&str]| {
+            let mut image = EcrImageDetail::new(
+                "123456789012",
+                "us-east-1",
+                "nginx",
+                digest,
+                vec![],
+                DateTime::from_secs(0),
+            );
+            image.child_digests = children.iter().map(|c| c.to_string()).collect();
+            image
+        };
+        let keep = BTreeMap::from([(KEEP_LABEL.to_string(), "true".to_string())]);
+        let mut labels = HashMap::from([
+            ("sha256:amd64".to_string(), BTreeMap::new()),
+            ("sha256:arm64".to_string(), keep.clone()),
+            ("sha256:other".to_string(), BTreeMap::new()),
+        ]);
+        let (index, other) = (
+            image("sha256:index", &["sha256:amd64", "sha256:arm64"]),
+            image("sha256:other-index", &["sha256:other"]),
+        );
+
+        inherit_labels(&mut labels, &[&index, &other]);
+        assert_eq!(labels.get("sha256:index"), Some(&keep));
+        assert_eq!(labels.get("sha256:other-index"), None);
+        assert!(is_protected_by_labels(
+            &EcrImageDetail {
+                labels: labels["sha256:index"].clone(),
+                ..index
+            },
+            SystemTime::UNIX_EPOCH
+        ));
+    }
+
+    #[test]
+    fn deletion_failures() {
+        let cases = vec![
+            (ImageFailureCode::ImageReferencedByManifestList, true),
+            (ImageFailureCode::KmsError, true),
+            (ImageFailureCode::ImageTagDoesNotMatchDigest, false),
+            (ImageFailureCode::InvalidImageDigest, false),
+        ];
+
+        for (code, retryable) in cases {
+            let failure = ImageFailure::builder()
+                .image_id(ImageIdentifier::builder().image_tag("v1").build())
+                .failure_code(code.clone())
+                .failure_reason("reason")
+                .build();
+            assert_eq!(is_retryable(&failure), retryable, "{:?}", code);
+
+            let actual = deletion_failure(&failure);
+            assert_eq!(actual.display_name(), "v1");
+            assert_eq!(actual.code, code.as_str());
+            assert_eq!(actual.reason, "reason");
+        }
+
+        // the identifier and the code may be missing in the response
+        let actual = deletion_failure(&ImageFailure::builder().build());
+        assert_eq!(actual.display_name(), "");
+        assert_eq!(actual.code, "");
+    }
+
+    #[test]
+    fn missing_manifest() {
+        let image = |digest: &str| {
+            Image::builder()
+                .image_id(ImageIdentifier::builder().image_digest(digest).build())
+                .image_manifest(r#"{"manifests":[]}"#)
+                .build()
+        };
+        let images = vec![image("sha256:a"), image("sha256:b")];
+
+        let manifests = collect_manifests("nginx", &images, &[]).unwrap();
+        assert_eq!(
+            manifests.keys().collect::<BTreeSet<_>>(),
+            BTreeSet::from([&"sha256:a".to_string(), &"sha256:b".to_string()])
+        );
+
+        // the parent index has been deleted since listed
+        let failures = vec![ImageFailure::builder()
+            .image_id(ImageIdentifier::builder().image_digest("sha256:c").build())
+            .failure_code(ImageFailureCode::ImageNotFound)
+            .failure_reason("Requested image not found")
+            .build()];
+        let err = collect_manifests("nginx", &images, &failures).unwrap_err();
+        assert!(err.to_string().contains("nginx@sha256:c"), "{}", err);
+    }
+
+    #[test]
+    fn deletion_limits() {
+        let limits = DeletionLimits {
+            max_images: Some(10),
+            max_percentage: Some(50.0),
+            max_bytes: Some(1000),
+        };
+
+        assert!(limits.check("repo", 10, 1000).is_empty());
+        assert_eq!(limits.check("repo", 11, 1001).len(), 2);
+        assert!(limits.check_percentage("repo", 5, 10).is_none());
+        assert!(limits.check_percentage("repo", 6, 10).is_some());
+        assert!(limits.check_percentage("repo", 1, 0).is_some());
+
+        let unlimited = DeletionLimits::default();
+        assert!(unlimited.check("repo", u64::MAX, u64::MAX).is_empty());
+        assert!(unlimited.check_percentage("repo", 10, 10).is_none());
+
+        // only the limits of the applicable filters apply
+        let item = |pattern: &str, max_images: u64| ImageFilterItem {
+            pattern: NamePattern::new(pattern).unwrap(),
+            limits: Some(DeletionLimits {
+                max_images: Some(max_images),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let filter = ImageFilter {
+            filters: vec![item("app-*", 10), item("*", 1)],
+            mode: FilterModeConfig::FirstMatch,
+            default: None,
+        };
+        let applicable = filter
+            .applicable("app-web")
+            .into_iter()
+            .filter_map(|f| f.limits.as_ref())
+            .collect::<Vec<_>>();
+        assert_eq!(applicable.len(), 1);
+        assert!(applicable[0].check("app-web", 5, 0).is_empty());
+    }
 }
@@ -1,5 +1,6 @@
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
 
+use aws_sdk_ecs::types::Service;
 use futures::TryStreamExt;
 use tokio::task::JoinSet;
 use tokio_stream::StreamExt;
@@ -58,9 +59,9 @@ impl ImageProvider for EcsServiceImageProvider {
                         .services()
                         .unwrap_or_default()
                         .iter()
-                        .filter_map(|svc| svc.task_definition())
+                        .flat_map(service_task_definitions)
                         .map(|td| td.to_string())
-                        .collect::<Vec<_>>();
+                        .collect::<BTreeSet<_>>();
 
                     let mut ret: HashSet<EcrImageId> = HashSet::new();
                     for td in tds.into_iter() {
@@ -94,6 +95,26 @@ impl ImageProvider for EcsServiceImageProvider {
                 Ok(acc)
             })
             .await
-            .map_err(ImageProviderError::from)
     }
 }
+
+/// The task definitions which the service runs. During a deployment, the deployments
+/// and the task sets may still run the task definitions other than the primary one.
+fn service_task_definitions(service: &Service) -> impl Iterator<Item = &str> {
+    let deployments = service
+        .deployments()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|d| d.task_definition());
+    let task_sets = service
+        .task_sets()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|t| t.task_definition());
+
+    service
+        .task_definition()
+        .into_iter()
+        .chain(deployments)
+        .chain(task_sets)
+}
@@ -0,0 +1,134 @@
+use std::collections::HashSet;
+
+use aws_sdk_ecs::types::{Container, DesiredStatus};
+use futures::TryStreamExt;
+use tokio::task::JoinSet;
+use tokio_stream::StreamExt;
+
+use crate::provider::{EcrImageId, ImageProvider, ImageProviderError};
+use crate::utils::try_join_set_to_stream;
+
+/// An ECR image provider from running ECS tasks, including the ones which no service manages
+pub struct EcsTaskImageProvider {
+    /// The AWS SDK client for ECS
+    client: aws_sdk_ecs::Client,
+}
+
+impl EcsTaskImageProvider {
+    pub fn from_conf(conf: &aws_config::SdkConfig) -> EcsTaskImageProvider {
+        let client = aws_sdk_ecs::Client::new(conf);
+        Self { client }
+    }
+}
+
+#[async_trait::async_trait]
+impl ImageProvider for EcsTaskImageProvider {
+    async fn provide_images(&self) -> Result<HashSet<EcrImageId>, ImageProviderError> {
+        let clusters: Vec<String> = self
+            .client
+            .list_clusters()
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<Result<Vec<_>, _>>()
+            .await?;
+
+        let mut tasks = JoinSet::new();
+        for cluster in clusters {
+            // the tasks desired to be running include the pending ones
+            let task_arns: Vec<String> = self
+                .client
+                .list_tasks()
+                .cluster(&cluster)
+                .desired_status(DesiredStatus::Running)
+                .into_paginator()
+                .items()
+                .send()
+                .collect::<Result<Vec<_>, _>>()
+                .await?;
+
+            task_arns.chunks(100).for_each(|chunk| {
+                let client = self.client.clone();
+                let chunk = chunk.to_vec();
+                let cluster = cluster.clone();
+                tasks.spawn(async move {
+                    let output = client
+                        .describe_tasks()
+                        .set_tasks(Some(chunk))
+                        .cluster(cluster)
+                        .send()
+                        .await?;
+
+                    let images = output
+                        .tasks()
+                        .unwrap_or_default()
+                        .iter()
+                        .flat_map(|t| t.containers().unwrap_or_default())
+                        .filter_map(container_image_id)
+                        .collect::<HashSet<_>>();
+
+                    Ok::<_, ImageProviderError>(images)
+                });
+            });
+        }
+
+        try_join_set_to_stream(tasks)
+            .try_fold(HashSet::new(), |mut acc, cur| async {
+                acc.extend(cur);
+                Ok(acc)
+            })
+            .await
+    }
+}
+
+/// The image of the container. The digest which the task resolved is added if reported,
+/// so that the image is protected even if the tag has been moved since then
+fn container_image_id(container: &Container) -> Option<EcrImageId> {
+    let mut id = EcrImageId::from_image_uri_opt(container.image()?)?;
+    if id.image_digest.is_none() {
+        id.image_digest = container.image_digest().map(|d| d.to_owned());
+    }
+    Some(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn container_image() {
+        let uri = "123456789012.dkr.ecr.us-east-1.amazonaws.com/nginx";
+        let id = |tag: Option<&str>, digest: Option<&str>| EcrImageId {
+            registry_id: "123456789012".to_string(),
+            region: "us-east-1".to_string(),
+            repository_name: "nginx".to_string(),
+            image_tag: tag.map(|t| t.to_string()),
+            image_digest: digest.map(|d| d.to_string()),
+        };
+
+        let cases = vec![
+            // a tag-only image along with the digest which the task resolved
+            (
+                format!("{}:v1", uri),
+                Some("sha256:a"),
+                Some(id(Some("v1"), Some("sha256:a"))),
+            ),
+            (format!("{}:v1", uri), None, Some(id(Some("v1"), None))),
+            // the pinned digest is kept
+            (
+                format!("{}@sha256:a", uri),
+                Some("sha256:b"),
+                Some(id(None, Some("sha256:a"))),
+            ),
+            ("nginx:latest".to_string(), Some("sha256:a"), None),
+        ];
+
+        for (image, digest, expected) in cases {
+            let container = Container::builder()
+                .image(&image)
+                .set_image_digest(digest.map(|d| d.to_string()))
+                .build();
+            assert_eq!(container_image_id(&container), expected, "{}", image);
+        }
+    }
+}
@@ -1,10 +1,11 @@
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
 
-use aws_sdk_lambda::types::{FunctionConfiguration, PackageType};
+use aws_sdk_lambda::types::{AliasConfiguration, FunctionConfiguration, PackageType};
 use futures::TryStreamExt;
 use tokio::task::JoinSet;
 use tokio_stream::StreamExt;
 
+use crate::config::LambdaScanConfig;
 use crate::provider::{EcrImageId, ImageProvider, ImageProviderError};
 use crate::utils::try_join_set_to_stream;
 
@@ -12,12 +13,21 @@ use crate::utils::try_join_set_to_stream;
 pub struct LambdaImageProvider {
     /// The AWS SDK client for Lambda
     client: aws_sdk_lambda::Client,
+    /// Whether to scan all the published versions, not only the ones aliases refer to
+    all_versions: bool,
 }
 
 impl LambdaImageProvider {
-    pub fn from_conf(conf: &aws_config::SdkConfig) -> LambdaImageProvider {
+    pub fn from_conf(
+        conf: &aws_config::SdkConfig,
+        scan: Option<&LambdaScanConfig>,
+    ) -> LambdaImageProvider {
         let client = aws_sdk_lambda::Client::new(conf);
-        Self { client }
+        let all_versions = scan.and_then(|s| s.all_versions).unwrap_or(false);
+        Self {
+            client,
+            all_versions,
+        }
     }
 }
 
@@ -37,29 +47,141 @@ impl ImageProvider for LambdaImageProvider {
         functions
             .into_iter()
             .filter(|f| f.package_type() == Some(&PackageType::Image))
-            .for_each(|f| {
+            .filter_map(|f| f.function_name().map(|s| s.to_owned()))
+            .for_each(|function_name| {
                 let client = self.client.clone();
+                let all_versions = self.all_versions;
                 tasks.spawn(async move {
-                    client
-                        .get_function()
-                        .set_function_name(f.function_name().map(|s| s.to_owned()))
+                    let aliases: Vec<AliasConfiguration> = client
+                        .list_aliases()
+                        .function_name(&function_name)
+                        .into_paginator()
+                        .items()
                         .send()
-                        .await
-                        .map(|output| {
-                            output
-                                .code()
-                                .and_then(|code| code.image_uri())
-                                .and_then(EcrImageId::from_image_uri_opt)
-                        })
-                        .map_err(ImageProviderError::from)
+                        .collect::<Result<Vec<_>, _>>()
+                        .await?;
+                    let mut versions = alias_versions(&aliases);
+                    if all_versions {
+                        let published: Vec<FunctionConfiguration> = client
+                            .list_versions_by_function()
+                            .function_name(&function_name)
+                            .into_paginator()
+                            .items()
+                            .send()
+                            .collect::<Result<Vec<_>, _>>()
+                            .await?;
+                        versions.extend(
+                            published
+                                .iter()
+                                .filter_map(|v| v.version())
+                                .map(|v| v.to_owned()),
+                        );
+                    }
+                    versions.remove(LATEST_VERSION);
+
+                    let mut ret = function_images(&client, &function_name, None).await?;
+                    for version in versions {
+                        let images = function_images(&client, &function_name, Some(&version));
+                        ret.extend(images.await?);
+                    }
+
+                    Ok::<_, ImageProviderError>(ret)
                 });
             });
 
         try_join_set_to_stream(tasks)
             .try_fold(HashSet::new(), |mut acc, cur| async {
-                cur.map(|cur| acc.insert(cur));
+                acc.extend(cur);
                 Ok(acc)
             })
             .await
     }
 }
+
+/// The version of a function which is not published
+const LATEST_VERSION: &str = "$LATEST";
+
+/// The versions which the aliases refer to, including the additional versions of weighted aliases
+fn alias_versions(aliases: &[AliasConfiguration]) -> BTreeSet<String> {
+    aliases
+        .iter()
+        .flat_map(|a| {
+            let weighted = a
+                .routing_config()
+                .and_then(|r| r.additional_version_weights())
+                .map(|w| w.keys().cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            a.function_version()
+                .map(|v| v.to_owned())
+                .into_iter()
+                .chain(weighted)
+        })
+        .collect()
+}
+
+/// The images of the version of the function, or of `$LATEST` if no version is given
+async fn function_images(
+    client: &aws_sdk_lambda::Client,
+    function_name: &str,
+    version: Option<&str>,
+) -> Result<HashSet<EcrImageId>, ImageProviderError> {
+    let output = client
+        .get_function()
+        .function_name(function_name)
+        .set_qualifier(version.map(|v| v.to_owned()))
+        .send()
+        .await?;
+
+    // the resolved uri holds the digest of the image actually deployed,
+    // even if the tag has been moved since then
+    Ok(output
+        .code()
+        .map(|code| [code.image_uri(), code.resolved_image_uri()])
+        .unwrap_or_default()
+        .into_iter()
+        .flatten()
+        .filter_map(EcrImageId::from_image_uri_opt)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_lambda::types::AliasRoutingConfiguration;
+
+    #[test]
+    fn versions_of_aliases() {
+        let alias = |version: Option<&str>, weights: &[&str]| {
+            let routing = weights
+                .iter()
+                .fold(AliasRoutingConfiguration::builder(), |builder, w| {
+                    builder.additional_version_weights(*w, 0.1)
+                });
+            AliasConfiguration::builder()
+                .set_function_version(version.map(|v| v.to_string()))
+                .routing_config(routing.build())
+                .build()
+        };
+
+        let cases = vec![
+            (vec![], vec![]),
+            (vec![alias(Some("3"), &[])], vec!["3"]),
+            (vec![alias(Some("3"), &["2"])], vec!["2", "3"]),
+            (vec![alias(None, &["2"])], vec!["2"]),
+            (
+                vec![alias(Some("3"), &["2"]), alias(Some("2"), &["1"])],
+                vec!["1", "2", "3"],
+            ),
+            (
+                vec![AliasConfiguration::builder().function_version("4").build()],
+                vec!["4"],
+            ),
+        ];
+
+        for (aliases, expected) in cases {
+            let actual = alias_versions(&aliases);
+            let expected = expected.into_iter().map(|v| v.to_string()).collect();
+            assert_eq!(actual, expected, "{:?}", aliases);
+        }
+    }
+}
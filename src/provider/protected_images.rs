@@ -0,0 +1,355 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use aws_sdk_ecr::types::{ImageIdentifier, Repository};
+use tokio_stream::StreamExt;
+
+use crate::pattern::NamePattern;
+use crate::provider::{EcrImageId, ImageProvider, ImageProviderError};
+
+/// An ECR image provider from local files listing the images to always protect
+pub struct ProtectedImagesProvider {
+    /// The AWS SDK client for ECR of the registry
+    client: aws_sdk_ecr::Client,
+    /// The images listed in the files
+    entries: Vec<ProtectedImage>,
+}
+
+impl ProtectedImagesProvider {
+    pub fn from_conf(
+        conf: &aws_config::SdkConfig,
+        paths: &[String],
+    ) -> Result<ProtectedImagesProvider, ImageProviderError> {
+        let client = aws_sdk_ecr::Client::new(conf);
+        let entries = paths
+            .iter()
+            .map(load_path)
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+        Ok(Self { client, entries })
+    }
+}
+
+#[async_trait::async_trait]
+impl ImageProvider for ProtectedImagesProvider {
+    async fn provide_images(&self) -> Result<HashSet<EcrImageId>, ImageProviderError> {
+        let mut ret = self
+            .entries
+            .iter()
+            .filter_map(|e| match e {
+                ProtectedImage::Uri(id) => Some(id.clone()),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+
+        // the other entries are resolved against the images in the registry
+        let needs_all = self.entries.iter().any(|e| {
+            matches!(
+                e,
+                ProtectedImage::Digest {
+                    repository_name: None,
+                    ..
+                }
+            )
+        });
+        let repository_names = self
+            .entries
+            .iter()
+            .filter_map(|e| e.repository_name())
+            .collect::<HashSet<_>>();
+        if !needs_all && repository_names.is_empty() {
+            return Ok(ret);
+        }
+
+        let repos: Vec<Repository> = self
+            .client
+            .describe_repositories()
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<Result<Vec<_>, _>>()
+            .await?;
+        let Some(region) = self.client.conf().region().map(|s| s.to_string()) else { return Ok(ret); };
+
+        for repo in repos {
+            let Some(registry_id) = repo.registry_id() else { continue; };
+            let Some(repository_name) = repo.repository_name() else { continue; };
+            if !needs_all && !repository_names.contains(repository_name) {
+                continue;
+            }
+
+            let ids: Vec<ImageIdentifier> = self
+                .client
+                .list_images()
+                .repository_name(repository_name)
+                .into_paginator()
+                .items()
+                .send()
+                .collect::<Result<Vec<_>, _>>()
+                .await?;
+            for id in ids {
+                let Some(digest) = id.image_digest() else { continue; };
+                if self
+                    .entries
+                    .iter()
+                    .any(|e| e.matches(repository_name, id.image_tag(), digest))
+                {
+                    ret.insert(EcrImageId {
+                        registry_id: registry_id.to_owned(),
+                        region: region.clone(),
+                        repository_name: repository_name.to_owned(),
+                        image_tag: None,
+                        image_digest: Some(digest.to_owned()),
+                    });
+                }
+            }
+        }
+
+        Ok(ret)
+    }
+}
+
+/// Load the images listed in the file. A relative path is resolved against the working directory.
+/// YAML files hold a list of entries, and the other files hold an entry per line with `#` comments.
+fn load_path(path: impl AsRef<Path>) -> Result<Vec<ProtectedImage>, ImageProviderError> {
+    let path = path.as_ref();
+    let load_error = |source: Box<dyn std::error::Error + Send + Sync>| {
+        ImageProviderError::initialization_error(LoadError {
+            path: path.display().to_string(),
+            source,
+        })
+    };
+    let content = std::fs::read_to_string(path).map_err(|e| load_error(e.into()))?;
+    let is_yaml = path.extension().is_some_and(|e| e == "yaml" || e == "yml");
+
+    let entries: Vec<String> = if is_yaml {
+        serde_yaml::from_str(&content).map_err(|e| load_error(e.into()))?
+    } else {
+        content
+            .lines()
+            .map(|l| l.split('#').next().unwrap_or_default().trim().to_owned())
+            .filter(|l| !l.is_empty())
+            .collect()
+    };
+
+    entries
+        .iter()
+        .map(|e| ProtectedImage::parse(e).map_err(|e| load_error(e.into())))
+        .collect()
+}
+
+/// An error reported when a file of the protected images cannot be loaded
+#[derive(Debug, thiserror::Error)]
+#[error("failed to load the protected images from {path}: {source}")]
+struct LoadError {
+    /// The path of the file
+    path: String,
+    /// The cause of the error
+    source: Box<dyn std::error::Error + Send + Sync>,
+}
+
+/// An image listed to protect
+#[cfg_attr(test, derive(Debug))]
+enum ProtectedImage {
+    /// An image URI such as `123456789012.dkr.ecr.us-east-1.amazonaws.com/repo:tag`
+    Uri(EcrImageId),
+    /// A digest such as `sha256:...`, in any repository unless given as `repo@sha256:...`
+    Digest {
+        repository_name: Option<String>,
+        digest: String,
+    },
+    /// The tags matching the pattern in the repository, given as `repo:tag-pattern`
+    Tags {
+        repository_name: String,
+        pattern: NamePattern,
+    },
+}
+
+impl ProtectedImage {
+    /// Parse an entry of the files
+    fn parse(entry: &str) -> Result<Self, InvalidEntryError> {
+        if let Some(id) = EcrImageId::from_image_uri_opt(entry) {
+            return Ok(Self::Uri(id));
+        }
+        if entry.starts_with("sha256:") {
+            return Ok(Self::Digest {
+                repository_name: None,
+                digest: entry.to_owned(),
+            });
+        }
+
+        let invalid = || InvalidEntryError {
+            entry: entry.to_owned(),
+        };
+        if let Some((repository_name, digest)) = entry.split_once('@') {
+            if repository_name.is_empty() || !digest.starts_with("sha256:") {
+                return Err(invalid());
+            }
+            return Ok(Self::Digest {
+                repository_name: Some(repository_name.to_owned()),
+                digest: digest.to_owned(),
+            });
+        }
+
+        let Some((repository_name, pattern)) = entry.split_once(':') else { return Err(invalid()); };
+        if repository_name.is_empty() || pattern.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self::Tags {
+            repository_name: repository_name.to_owned(),
+            pattern: NamePattern::new(pattern).map_err(|_| invalid())?,
+        })
+    }
+
+    /// The repository which the entry is limited to, except for image URIs
+    fn repository_name(&self) -> Option<&str> {
+        match self {
+            Self::Uri(_) => None,
+            Self::Digest {
+                repository_name, ..
+            } => repository_name.as_deref(),
+            Self::Tags {
+                repository_name, ..
+            } => Some(repository_name),
+        }
+    }
+
+    /// Decide whether the image in the registry is listed by this entry
+    fn matches(&self, repository_name: &str, tag: Option<&str>, digest: &str) -> bool {
+        match self {
+            Self::Uri(_) => false,
+            Self::Digest {
+                repository_name: repo,
+                digest: d,
+            } => repo.as_deref().is_none_or(|r| r == repository_name) && d == digest,
+            Self::Tags {
+                repository_name: repo,
+                pattern,
+            } => repo == repository_name && tag.is_some_and(|t| pattern.matches(t)),
+        }
+    }
+}
+
+/// An error reported when an entry of the protected images cannot be parsed
+#[derive(Debug, thiserror::Error)]
+#[error("invalid protected image `{entry}`: expected an image URI, a digest or `repository:tag`")]
+struct InvalidEntryError {
+    /// The offending entry
+    entry: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protected_image() {
+        let cases = vec![
+            (
+                "123456789012.dkr.ecr.us-east-1.amazonaws.com/nginx:1.25",
+                ("nginx", Some("1.25"), "sha256:a"),
+                false,
+            ),
+            ("sha256:a", ("nginx", None, "sha256:a"), true),
+            ("sha256:a", ("nginx", None, "sha256:b"), false),
+            ("nginx@sha256:a", ("nginx", None, "sha256:a"), true),
+            ("nginx@sha256:a", ("redis", None, "sha256:a"), false),
+            (
+                "nginx:release-*",
+                ("nginx", Some("release-1"), "sha256:a"),
+                true,
+            ),
+            (
+                "nginx:release-*",
+                ("nginx", Some("dev-1"), "sha256:a"),
+                false,
+            ),
+            ("nginx:release-*", ("nginx", None, "sha256:a"), false),
+            (
+                "app/nginx:release-*",
+                ("app/nginx", Some("release-1"), "sha256:a"),
+                true,
+            ),
+            (
+                "nginx:regex:^v\\d+$",
+                ("nginx", Some("v1"), "sha256:a"),
+                true,
+            ),
+        ];
+
+        for (entry, (repository_name, tag, digest), expected) in cases {
+            let actual =
+                ProtectedImage::parse(entry)
+                    .unwrap()
+                    .matches(repository_name, tag, digest);
+            assert_eq!(actual, expected, "{} {:?}", entry, tag);
+        }
+
+        for entry in ["nginx", "nginx:", ":latest", "nginx@latest", "nginx:[a-"] {
+            let err = ProtectedImage::parse(entry).unwrap_err();
+            assert!(err.to_string().contains(entry), "{}", err);
+        }
+    }
+
+    #[test]
+    fn load() {
+        let dir = std::env::temp_dir().join(format!("dyson-protected-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, content: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, content).unwrap();
+            path
+        };
+
+        let cases = vec![
+            (
+                write(
+                    "protected.txt",
+                    "# rollback targets\nnginx:release-*  # releases\n\n  sha256:a\n",
+                ),
+                Some(vec![
+                    ("nginx", Some("release-1"), "sha256:b"),
+                    ("redis", None, "sha256:a"),
+                ]),
+            ),
+            (
+                write(
+                    "protected.yaml",
+                    "- nginx:release-*\n- \"sha256:a\" # comment\n",
+                ),
+                Some(vec![
+                    ("nginx", Some("release-1"), "sha256:b"),
+                    ("redis", None, "sha256:a"),
+                ]),
+            ),
+            (write("invalid.txt", "nginx\n"), None),
+            (write("invalid.yml", "nginx: latest\n"), None),
+            (dir.join("missing.txt"), None),
+        ];
+
+        for (path, expected) in cases {
+            match (load_path(&path), expected) {
+                (Ok(entries), Some(images)) => {
+                    assert_eq!(entries.len(), images.len(), "{}", path.display());
+                    for (entry, (repository_name, tag, digest)) in entries.iter().zip(images) {
+                        assert!(
+                            entry.matches(repository_name, tag, digest),
+                            "{} {:?}",
+                            path.display(),
+                            entry
+                        );
+                    }
+                }
+                (Err(err), None) => {
+                    let name = path.file_name().unwrap().to_string_lossy();
+                    assert!(err.to_string().contains(name.as_ref()), "{}", err);
+                }
+                (actual, _) => panic!("{}: {:?}", path.display(), actual.map(|_| ())),
+            }
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
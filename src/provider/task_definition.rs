@@ -1,82 +1,143 @@
 use std::collections::HashSet;
+use std::time::SystemTime;
 
-use aws_sdk_ecs::types::{SortOrder, TaskDefinitionFamilyStatus, TaskDefinitionStatus};
-use futures::TryStreamExt;
+use aws_sdk_ecs::types::{
+    SortOrder, TaskDefinition, TaskDefinitionFamilyStatus, TaskDefinitionStatus,
+};
+use futures::{Future, Stream, TryStreamExt};
 use tokio::task::JoinSet;
 use tokio_stream::StreamExt;
 
+use crate::config::{RevisionRetentionConfig, TaskDefinitionScanConfig};
+use crate::pattern::NamePattern;
 use crate::provider::{EcrImageId, ImageProvider, ImageProviderError};
-use crate::utils::try_join_set_to_stream;
+use crate::utils::{days_before, try_join_set_to_stream};
+
+/// The number of the latest revisions to keep in each family by default
+const DEFAULT_KEEP_LATEST: usize = 2;
 
 /// An ECR image source from Task Definitions
 pub struct TaskDefinitionProvider {
     /// The AWS SDK client for ECS
     client: aws_sdk_ecs::Client,
-    /// The maximum number of results to return in a single family.
-    max_result: i32,
+    /// The retention for the families which no family rule matches
+    retention: RevisionRetention,
+    /// The retention per family pattern. The first one matching a family applies
+    families: Vec<(NamePattern, RevisionRetention)>,
 }
 
 impl TaskDefinitionProvider {
-    pub fn from_conf(conf: &aws_config::SdkConfig) -> TaskDefinitionProvider {
+    pub fn from_conf(
+        conf: &aws_config::SdkConfig,
+        scan: Option<&TaskDefinitionScanConfig>,
+    ) -> Result<TaskDefinitionProvider, ImageProviderError> {
         let client = aws_sdk_ecs::Client::new(conf);
-        let max_result = 2; // Fixme: Make configurable
-        Self { client, max_result }
+        let base = RevisionRetention {
+            keep_latest: DEFAULT_KEEP_LATEST,
+            keep_days: None,
+            include_inactive: false,
+        };
+        let retention = scan.map_or(base, |s| base.with(&s.rule));
+        let families = scan
+            .and_then(|s| s.families.as_ref())
+            .unwrap_or(&Vec::new())
+            .iter()
+            .map(|f| {
+                NamePattern::new(f.pattern.as_str())
+                    .map(|p| (p, retention.with(&f.rule)))
+                    .map_err(ImageProviderError::initialization_error)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            client,
+            retention,
+            families,
+        })
+    }
+
+    /// The retention which applies to the family
+    fn retention(&self, family: &str) -> RevisionRetention {
+        self.families
+            .iter()
+            .find(|(p, _)| p.matches(family))
+            .map_or(self.retention, |(_, r)| *r)
     }
 }
 
 #[async_trait::async_trait]
 impl ImageProvider for TaskDefinitionProvider {
     async fn provide_images(&self) -> Result<HashSet<EcrImageId>, ImageProviderError> {
+        let include_inactive = self.retention.include_inactive
+            || self.families.iter().any(|(_, r)| r.include_inactive);
+        let status = if include_inactive {
+            TaskDefinitionFamilyStatus::All
+        } else {
+            TaskDefinitionFamilyStatus::Active
+        };
         let families: Vec<String> = self
             .client
             .list_task_definition_families()
-            .status(TaskDefinitionFamilyStatus::Active)
+            .status(status)
             .into_paginator()
             .items()
             .send()
             .collect::<Result<Vec<_>, _>>()
             .await?;
 
+        let now = SystemTime::now();
         let mut tasks = JoinSet::new();
         families.into_iter().for_each(|fam| {
             let client = self.client.clone();
-            let max_result = self.max_result;
+            let retention = self.retention(&fam);
             tasks.spawn(async move {
-                let tds = client
-                    .list_task_definitions()
-                    .family_prefix(&fam)
-                    .status(TaskDefinitionStatus::Active)
-                    .sort(SortOrder::Desc)
-                    .max_results(max_result)
-                    .into_paginator()
-                    .items()
-                    .send()
-                    .collect::<Result<Vec<_>, _>>()
-                    .await?;
-
-                let mut ret: HashSet<EcrImageId> = HashSet::new();
-
-                for td in tds.iter() {
-                    let def = client
-                        .describe_task_definition()
-                        .task_definition(td)
+                let list = |status: TaskDefinitionStatus| {
+                    client
+                        .list_task_definitions()
+                        .family_prefix(&fam)
+                        .status(status)
+                        .sort(SortOrder::Desc)
+                        .into_paginator()
+                        .items()
                         .send()
-                        .await?;
-
-                    let cs = def
-                        .task_definition()
-                        .and_then(|td| td.container_definitions())
-                        .unwrap_or_default();
-
-                    let images = cs
-                        .iter()
-                        .filter_map(|c| c.image())
-                        .filter_map(EcrImageId::from_image_uri_opt)
-                        .collect::<HashSet<_>>();
-                    ret.extend(images);
-                }
-
-                Ok::<_, ImageProviderError>(ret)
+                };
+                // the days beyond the representable time keep all the revisions
+                let registered_after = retention.keep_days.map(|days| {
+                    aws_smithy_types::DateTime::from(
+                        days_before(now, days).unwrap_or(SystemTime::UNIX_EPOCH),
+                    )
+                });
+
+                let defs = retained_revisions(
+                    &fam,
+                    list(TaskDefinitionStatus::Active),
+                    retention
+                        .include_inactive
+                        .then(|| list(TaskDefinitionStatus::Inactive)),
+                    retention,
+                    registered_after,
+                    |td| {
+                        let client = client.clone();
+                        async move {
+                            let output = client
+                                .describe_task_definition()
+                                .task_definition(td)
+                                .send()
+                                .await?;
+                            Ok(output.task_definition().cloned())
+                        }
+                    },
+                )
+                .await?;
+
+                let images = defs
+                    .iter()
+                    .flat_map(|def| def.container_definitions().unwrap_or_default())
+                    .filter_map(|c| c.image())
+                    .filter_map(EcrImageId::from_image_uri_opt)
+                    .collect::<HashSet<_>>();
+
+                Ok::<_, ImageProviderError>(images)
             });
         });
 
@@ -86,6 +147,279 @@ impl ImageProvider for TaskDefinitionProvider {
                 Ok(acc)
             })
             .await
-            .map_err(ImageProviderError::from)
+    }
+}
+
+/// Select the revisions of the family to keep. The window of the retention applies to the ACTIVE revisions
+/// given from the latest, which are listed only until the window is satisfied. The INACTIVE revisions are all
+/// kept if given, as they never push the ACTIVE ones out of the window.
+async fn retained_revisions<A, I, E, D, F>(
+    family: &str,
+    active: A,
+    inactive: Option<I>,
+    retention: RevisionRetention,
+    registered_after: Option<aws_smithy_types::DateTime>,
+    describe: D,
+) -> Result<Vec<TaskDefinition>, ImageProviderError>
+where
+    A: Stream<Item = Result<String, E>>,
+    I: Stream<Item = Result<String, E>>,
+    E: Into<ImageProviderError>,
+    D: Fn(String) -> F,
+    F: Future<Output = Result<Option<TaskDefinition>, ImageProviderError>>,
+{
+    let mut ret = Vec::new();
+    let mut kept = 0;
+
+    let mut active = std::pin::pin!(active);
+    loop {
+        // stop before the next page is requested
+        let is_latest = kept < retention.keep_latest;
+        if !is_latest && registered_after.is_none() {
+            break;
+        }
+        let Some(td) = active.next().await else { break; };
+        let td = td.map_err(|e| e.into())?;
+        // the prefix also matches the other families such as `web-admin` for `web`
+        if parse_revision(&td, family).is_none() {
+            continue;
+        }
+
+        let Some(def) = describe(td).await? else { continue; };
+
+        // the older revisions are registered even earlier
+        let is_recent = def
+            .registered_at()
+            .zip(registered_after.as_ref())
+            .is_some_and(|(at, after)| at.as_secs_f64() >= after.as_secs_f64());
+        if !is_latest && !is_recent {
+            break;
+        }
+        ret.push(def);
+        kept += 1;
+    }
+
+    let Some(inactive) = inactive else { return Ok(ret); };
+    let mut inactive = std::pin::pin!(inactive);
+    while let Some(td) = inactive.next().await {
+        let td = td.map_err(|e| e.into())?;
+        if parse_revision(&td, family).is_none() {
+            continue;
+        }
+        ret.extend(describe(td).await?);
+    }
+
+    Ok(ret)
+}
+
+/// The retention of task definition revisions to consider as used
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RevisionRetention {
+    /// the number of the latest revisions to keep
+    keep_latest: usize,
+    /// the revisions registered within this days are kept
+    keep_days: Option<u64>,
+    /// whether all INACTIVE revisions are kept in addition to the ACTIVE ones
+    include_inactive: bool,
+}
+
+impl RevisionRetention {
+    /// The retention with the options given by the config, and the others follow this
+    fn with(&self, conf: &RevisionRetentionConfig) -> Self {
+        Self {
+            keep_latest: conf.keep_latest.map_or(self.keep_latest, |n| n as usize),
+            keep_days: conf.keep_days.or(self.keep_days),
+            include_inactive: conf.include_inactive.unwrap_or(self.include_inactive),
+        }
+    }
+}
+
+/// Parse the revision of a task definition ARN such as
+/// `arn:aws:ecs:us-east-1:123456789012:task-definition/web:12` if it belongs to the family exactly
+fn parse_revision(arn: &str, family: &str) -> Option<i32> {
+    let (_, name) = arn.rsplit_once('/')?;
+    let (fam, revision) = name.rsplit_once(':')?;
+    if fam != family {
+        return None;
+    }
+    revision.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revision() {
+        let cases = vec![
+            (
+                "arn:aws:ecs:us-east-1:123456789012:task-definition/web:12",
+                "web",
+                Some(12),
+            ),
+            (
+                "arn:aws:ecs:us-east-1:123456789012:task-definition/web-admin:3",
+                "web",
+                None,
+            ),
+            (
+                "arn:aws:ecs:us-east-1:123456789012:task-definition/web-admin:3",
+                "web-admin",
+                Some(3),
+            ),
+            (
+                "arn:aws:ecs:us-east-1:123456789012:task-definition/web",
+                "web",
+                None,
+            ),
+        ];
+
+        for (arn, family, expected) in cases {
+            assert_eq!(parse_revision(arn, family), expected, "{} {}", arn, family);
+        }
+    }
+
+    #[test]
+    fn retention() {
+        let conf = TaskDefinitionScanConfig {
+            rule: RevisionRetentionConfig {
+                keep_latest: None,
+                keep_days: Some(30),
+                include_inactive: None,
+            },
+            families: Some(vec![crate::config::TaskDefinitionFamilyConfig {
+                pattern: "batch-*".to_string(),
+                rule: RevisionRetentionConfig {
+                    keep_latest: Some(5),
+                    keep_days: None,
+                    include_inactive: Some(true),
+                },
+            }]),
+        };
+        let provider = TaskDefinitionProvider::from_conf(
+            &aws_config::SdkConfig::builder().build(),
+            Some(&conf),
+        )
+        .unwrap();
+
+        let cases = vec![
+            (
+                "web",
+                RevisionRetention {
+                    keep_latest: DEFAULT_KEEP_LATEST,
+                    keep_days: Some(30),
+                    include_inactive: false,
+                },
+            ),
+            (
+                "batch-daily",
+                RevisionRetention {
+                    keep_latest: 5,
+                    keep_days: Some(30),
+                    include_inactive: true,
+                },
+            ),
+        ];
+
+        for (family, expected) in cases {
+            assert_eq!(provider.retention(family), expected, "{}", family);
+        }
+    }
+
+    #[tokio::test]
+    async fn retained() {
+        let arn = |revision: &str| {
+            format!(
+                "arn:aws:ecs:us-east-1:123456789012:task-definition/{}",
+                revision
+            )
+        };
+        let revisions = |revisions: &[&str]| {
+            futures::stream::iter(
+                revisions
+                    .iter()
+                    .map(|r| Ok::<_, ImageProviderError>(arn(r)))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        // the revisions are registered a day apart up to :10
+        let describe = |td: String| async move {
+            let revision = parse_revision(&td, "web").unwrap_or_default() as i64;
+            Ok(Some(
+                TaskDefinition::builder()
+                    .task_definition_arn(td)
+                    .registered_at(aws_smithy_types::DateTime::from_secs(
+                        revision * 24 * 60 * 60,
+                    ))
+                    .build(),
+            ))
+        };
+        let retention = |keep_latest: usize, include_inactive: bool| RevisionRetention {
+            keep_latest,
+            keep_days: None,
+            include_inactive,
+        };
+
+        let cases = vec![
+            (
+                "the INACTIVE revisions do not push the ACTIVE ones out of the window",
+                vec!["web:10"],
+                Some(vec!["web:12", "web:11"]),
+                retention(2, true),
+                None,
+                vec!["web:10", "web:12", "web:11"],
+            ),
+            (
+                "the revisions of the other families are skipped",
+                vec!["web:10", "web-admin:9", "web:8", "web:7"],
+                None,
+                retention(2, false),
+                None,
+                vec!["web:10", "web:8"],
+            ),
+            (
+                "the revisions registered within the days are kept beyond the latest",
+                vec!["web:10", "web:9", "web:8", "web:7"],
+                None,
+                retention(1, false),
+                Some(aws_smithy_types::DateTime::from_secs(8 * 24 * 60 * 60)),
+                vec!["web:10", "web:9", "web:8"],
+            ),
+        ];
+
+        for (name, active, inactive, retention, registered_after, expected) in cases {
+            let actual = retained_revisions(
+                "web",
+                revisions(&active),
+                inactive.as_deref().map(revisions),
+                retention,
+                registered_after,
+                describe,
+            )
+            .await
+            .unwrap()
+            .iter()
+            .filter_map(|d| d.task_definition_arn().map(|a| a.to_owned()))
+            .collect::<Vec<_>>();
+            let expected = expected.into_iter().map(arn).collect::<Vec<_>>();
+            assert_eq!(actual, expected, "{}", name);
+        }
+
+        // the ACTIVE revisions are no longer listed once the window is satisfied
+        let active = futures::stream::iter(vec![
+            Ok(arn("web:10")),
+            Ok(arn("web:9")),
+            Err(ImageProviderError::initialization_error(std::fmt::Error)),
+        ]);
+        let actual = retained_revisions(
+            "web",
+            active,
+            None::<futures::stream::Empty<_>>,
+            retention(2, false),
+            None,
+            describe,
+        )
+        .await;
+        assert_eq!(actual.unwrap().len(), 2);
     }
 }
@@ -1,24 +1,238 @@
 use prettytable::{row, Table};
 
-use crate::image::ImagesSummary;
+use crate::config::SeverityConfig;
+use crate::image::{
+    CleanupAction, DeletionFailures, EcrImageDetail, EcrImageTarget, ImagesSummary,
+};
 
 /// writes the summary of images
 pub fn write_summary(summary: &ImagesSummary, output: &mut impl std::io::Write) {
     let mut table = Table::new();
 
-    table.add_row(row!["Repo", "Tags", "Total"]);
+    table.add_row(row!["Repo", "Delete", "Untag only", "Total", "Size"]);
 
-    for (repo, tags) in summary {
-        let formatted_tags =
-            tags.iter()
-                .filter_map(|tag| tag.image_tag())
-                .fold(String::new(), |mut acc, tag| {
-                    acc.push_str(&format!("{}\n", tag));
-                    acc
-                });
+    let mut total_bytes = 0;
+    for (repo, targets) in summary {
+        let (mut deleted, mut untagged) = (String::new(), String::new());
+        for target in targets {
+            match target.action() {
+                CleanupAction::Delete => deleted.push_str(&format!(
+                    "{} ({})\n",
+                    target.image.display_name(),
+                    format_size(target.reclaimed_bytes())
+                )),
+                CleanupAction::Untag => {
+                    untagged.push_str(&format!("{}\n", target.target_tags.join(", ")))
+                }
+            }
+        }
 
-        table.add_row(row![repo, formatted_tags, tags.len()]);
+        let bytes = repository_bytes(targets);
+        total_bytes += bytes;
+        table.add_row(row![
+            repo,
+            deleted,
+            untagged,
+            targets.len(),
+            format_size(bytes)
+        ]);
+    }
+
+    table.add_row(row!["Total", "", "", "", format_size(total_bytes)]);
+
+    let _ = table.print(output);
+}
+
+/// The size of the storage freed in the repository in bytes
+pub fn repository_bytes(targets: &[EcrImageTarget]) -> i64 {
+    targets.iter().map(|t| t.reclaimed_bytes()).sum()
+}
+
+/// Format a size in bytes in a human readable form
+pub fn format_size(bytes: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size.abs() >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        return format!("{} {}", bytes, UNITS[0]);
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// writes the total number of images per action
+pub fn write_totals(summary: &ImagesSummary, output: &mut impl std::io::Write) {
+    let targets = summary.values().flatten();
+    let deleted = targets
+        .clone()
+        .filter(|t| t.action() == CleanupAction::Delete)
+        .count();
+    let untagged = targets.count() - deleted;
+
+    let _ = writeln!(
+        output,
+        "Plan: {} images to delete, {} images to untag.",
+        deleted, untagged
+    );
+}
+
+/// writes the storage freed by image size and by unique layers
+pub fn write_reclaimed(
+    summary: &ImagesSummary,
+    layer_bytes: i64,
+    output: &mut impl std::io::Write,
+) {
+    let total_bytes = summary.values().map(|v| repository_bytes(v)).sum();
+
+    let _ = writeln!(
+        output,
+        "Storage: {} by image size, {} by unique layers.",
+        format_size(total_bytes),
+        format_size(layer_bytes)
+    );
+}
+
+/// The images in use to report along with their findings of the severity or more severe.
+/// The findings come from the findings summary of ECR image scanning, so the images without
+/// a completed scan are reported as well. Image indexes are left to their children.
+pub fn vulnerability_report(
+    images: &[EcrImageDetail],
+    severity: SeverityConfig,
+) -> Vec<(String, String, String)> {
+    let mut images = images
+        .iter()
+        .filter(|i| i.child_digests.is_empty())
+        .filter(|i| !i.is_scanned() || i.findings_at_least(severity) > 0)
+        .collect::<Vec<_>>();
+    images.sort_by(|a, b| a.id.repository_name.cmp(&b.id.repository_name));
+
+    images
+        .into_iter()
+        .map(|image| {
+            let findings = if image.is_scanned() {
+                image
+                    .finding_severity_counts
+                    .iter()
+                    .map(|(s, c)| format!("{}: {}", s, c))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            } else {
+                format!(
+                    "not scanned ({})",
+                    image.scan_status.as_deref().unwrap_or("NO_SCAN")
+                )
+            };
+            (
+                image.id.repository_name.clone(),
+                image.display_name(),
+                findings,
+            )
+        })
+        .collect()
+}
+
+/// writes the images in use which have findings of the severity or more severe,
+/// and the ones without a completed scan
+pub fn write_vulnerabilities(
+    images: &[EcrImageDetail],
+    severity: SeverityConfig,
+    output: &mut impl std::io::Write,
+) {
+    let mut table = Table::new();
+
+    table.add_row(row!["Repo", "Image", "Findings"]);
+
+    for (repo, image, findings) in vulnerability_report(images, severity) {
+        table.add_row(row![repo, image, findings]);
     }
 
     let _ = table.print(output);
 }
+
+/// writes the images failed to be deleted
+pub fn write_failures(failures: &DeletionFailures, output: &mut impl std::io::Write) {
+    let mut table = Table::new();
+
+    table.add_row(row!["Repo", "Image", "Code", "Reason"]);
+
+    for (repo, failures) in failures {
+        for failure in failures {
+            table.add_row(row![
+                repo,
+                failure.display_name(),
+                failure.code,
+                failure.reason
+            ]);
+        }
+    }
+
+    let _ = table.print(output);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_format() {
+        let cases = vec![
+            (0, "0 B"),
+            (1023, "1023 B"),
+            (1024, "1.0 KiB"),
+            (1536, "1.5 KiB"),
+            (5 * 1024 * 1024 * 1024, "5.0 GiB"),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(format_size(input), expected);
+        }
+    }
+
+    #[test]
+    fn vulnerabilities() {
+        let image = |digest: &str, tag: &str, status: Option<&str>, counts: &[(&str, i64)]| {
+            let mut image = EcrImageDetail::new(
+                "123456789012",
+                "us-east-1",
+                "nginx",
+                digest,
+                vec![tag.to_string()],
+                aws_smithy_types::DateTime::from_secs(0),
+            );
+            image.scan_status = status.map(|s| s.to_string());
+            image.finding_severity_counts =
+                counts.iter().map(|(s, c)| (s.to_string(), *c)).collect();
+            image
+        };
+        let mut index = image("sha256:e", "multi", None, &[]);
+        index.child_digests = vec!["sha256:a".to_string()];
+        let images = vec![
+            image("sha256:a", "critical", Some("COMPLETE"), &[("CRITICAL", 1)]),
+            image("sha256:b", "low", Some("COMPLETE"), &[("LOW", 3)]),
+            image("sha256:c", "pending", Some("IN_PROGRESS"), &[]),
+            image("sha256:d", "never", None, &[]),
+            index,
+        ];
+
+        let actual = vulnerability_report(&images, SeverityConfig::High)
+            .into_iter()
+            .map(|(_, image, findings)| (image, findings))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actual,
+            vec![
+                ("critical".to_string(), "CRITICAL: 1".to_string()),
+                (
+                    "pending".to_string(),
+                    "not scanned (IN_PROGRESS)".to_string()
+                ),
+                ("never".to_string(), "not scanned (NO_SCAN)".to_string()),
+            ]
+        );
+    }
+}
@@ -19,3 +19,9 @@ pub fn try_join_set_to_stream<T: 'static, E: 'static>(
         }
     })
 }
+
+/// The time the given days before `now`, or `None` if it cannot be represented.
+pub fn days_before(now: std::time::SystemTime, days: u64) -> Option<std::time::SystemTime> {
+    days.checked_mul(24 * 60 * 60)
+        .and_then(|secs| now.checked_sub(std::time::Duration::from_secs(secs)))
+}